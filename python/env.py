@@ -11,6 +11,7 @@ API_SCREEN = "http://127.0.0.1:7878/visual_observations"
 API_STEP = "http://127.0.0.1:7878/step"
 API_RESET = "http://127.0.0.1:7878/reset"
 API_STATE = "http://127.0.0.1:7878/state"
+API_SEED = "http://127.0.0.1:7879/seed"
 
 ACTION_MAP = {
     0: "IDLE",
@@ -21,29 +22,73 @@ ACTION_MAP = {
     5: "FORWARD",
     6: "BACKWARD",
     7: "SHOOT",
+    8: "RELOAD",
+    9: "NEXT_WEAPON",
 }
 
 
+# Bit order of the `Actions` flags, index i of a MultiBinary action sets bit i
+ACTION_FLAGS = [
+    "IDLE",
+    "FORWARD",
+    "BACKWARD",
+    "LEFT",
+    "RIGHT",
+    "TURN_LEFT",
+    "TURN_RIGHT",
+    "SHOOT",
+    "RELOAD",
+    "NEXT_WEAPON",
+]
+
+
+def encode_action(action, action_space):
+    """Encode a single agent action for the step request"""
+    if action_space == "continuous":
+        # [move_x, move_z, turn, fire, reload, next_weapon], the environment must run with --action-space continuous
+        return json.dumps([float(v) for v in action])
+    if action_space == "multi_binary":
+        return str(sum(1 << i for i, bit in enumerate(action) if bit))
+    return ACTION_MAP[int(action)]
+
+
 class Environment:
-    def __init__(self, size, number_of_agents):
+    def __init__(
+        self, size, number_of_agents, action_space="discrete", passes=("color",)
+    ):
         self.size = size
         self.number_of_agents = number_of_agents
+        # Must match the environment: "color", then "depth" with --depth-range,
+        # then "segmentation" with --segmentation
+        self.passes = passes
+        self.pass_images = {}
         self.observation_space = spaces.Box(
             low=0,
             high=255,
             shape=(self.number_of_agents, size[0], size[1], 1),
             dtype=np.uint8,
         )
-        self.action_space = spaces.Discrete(8)
+        self.action_space_type = action_space
+        if action_space == "continuous":
+            self.action_space = spaces.Box(low=-1.0, high=1.0, shape=(6,), dtype=np.float32)
+        elif action_space == "multi_binary":
+            self.action_space = spaces.MultiBinary(len(ACTION_FLAGS))
+        else:
+            self.action_space = spaces.Discrete(len(ACTION_MAP))
         self.metadata = {}
         self.images = []
 
     def reset(self, seed=None):
+        if seed is not None:
+            # Staged for the next reset without stepping the simulation
+            requests.post(API_SEED, data=str(seed)).raise_for_status()
         requests.post(API_RESET)
         return self.visual_observations(), None
 
     def step(self, actions):
-        actions = [{"action": ACTION_MAP[a]} for a in actions]
+        actions = [
+            {"action": encode_action(a, self.action_space_type)} for a in actions
+        ]
         action_json = json.dumps(actions, indent=4)
         response = requests.get(API_STEP, params={"payload": action_json})
 
@@ -66,6 +111,50 @@ class Environment:
     def state(self):
         return requests.get(API_STATE).json()
 
+    def proprioception(self, state=None):
+        """Per-agent feature vectors, requires running with --proprioception"""
+        state = state or self.state()
+        return np.array(
+            [
+                [
+                    p["health"],
+                    *p["velocity"],
+                    p["angular_velocity"],
+                    p["heading_sin"],
+                    p["heading_cos"],
+                    p["time_remaining"],
+                    float(p["last_shot_hit"]),
+                    p["weapon"],
+                    p["magazine"],
+                    p["reserve"],
+                    p["cooldown"],
+                    p["reload"],
+                ]
+                for p in state["proprioception"]
+            ],
+            dtype=np.float32,
+        )
+
+    def lidar(self, state=None):
+        """Per-agent (distances, hit types) arrays, requires running with --lidar-rays"""
+        state = state or self.state()
+        hit_types = {"nothing": 0, "wall": 1, "enemy": 2, "teammate": 3}
+        distances = np.array([s["distances"] for s in state["lidar"]], dtype=np.float32)
+        hits = np.array(
+            [[hit_types[h] for h in s["hits"]] for s in state["lidar"]], dtype=np.uint8
+        )
+        return distances, hits
+
+    def occupancy(self, state=None):
+        """Per-agent (channels, rows, columns) uint8 grids, requires running with --occupancy-size"""
+        state = state or self.state()
+        grids = []
+        for grid in state["occupancy"]:
+            shape = grid["shape"]
+            bits = np.unpackbits(np.array(grid["data"], dtype=np.uint8))
+            grids.append(bits[: np.prod(shape)].reshape(shape))
+        return np.stack(grids)
+
     def map(self):
         state = self.state()
         positions = state["map"]["walls"]
@@ -97,16 +186,21 @@ class Environment:
         image = Image.fromarray(image.astype("uint8"), "RGBA")
 
         self.images = []
+        self.pass_images = {p: [] for p in self.passes}
+        tile_width = image.width / (self.number_of_agents * len(self.passes))
         for n in range(0, self.number_of_agents):
-            crop_rectangle = (
-                n * (image.width / self.number_of_agents),
-                0,
-                (n + 1) * (image.width / self.number_of_agents),
-                image.height,
-            )
-
-            cropped_image = image.crop(crop_rectangle)
-            cropped_image.thumbnail(self.size)
-            self.images.append(np.asarray(ImageOps.grayscale(cropped_image)))
-
+            for k, name in enumerate(self.passes):
+                left = (n * len(self.passes) + k) * tile_width
+                crop_rectangle = (left, 0, left + tile_width, image.height)
+
+                cropped_image = image.crop(crop_rectangle)
+                cropped_image.thumbnail(self.size)
+                if name == "segmentation":
+                    # keep class colors
+                    pixels = np.asarray(cropped_image.convert("RGB"))
+                else:
+                    pixels = np.asarray(ImageOps.grayscale(cropped_image))
+                self.pass_images[name].append(pixels)
+
+        self.images = self.pass_images["color"]
         return self.images
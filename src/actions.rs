@@ -1,7 +1,8 @@
 use bitflags::bitflags;
+use serde::Deserialize;
 
 bitflags! {
-    #[derive(Default, Clone)]
+    #[derive(Default, Clone, Debug, PartialEq, Eq)]
     pub struct Actions: u32 {
         const IDLE = 1 << 0;
         const FORWARD = 1 << 1;
@@ -11,5 +12,199 @@ bitflags! {
         const TURN_LEFT = 1 << 5;
         const TURN_RIGHT = 1 << 6;
         const SHOOT = 1 << 7;
+        const RELOAD = 1 << 8;
+        const NEXT_WEAPON = 1 << 9;
+    }
+}
+
+impl Actions {
+    /// Parse an agent action from the control protocol. Accepted forms are a flag name
+    /// (`"FORWARD"`), flag names joined with `|` (`"FORWARD|SHOOT"`), a JSON array of flag
+    /// names (`["FORWARD", "SHOOT"]`) or an integer bitmask (`"130"`).
+    ///
+    /// Unknown flag names and bits make the whole action invalid.
+    pub(crate) fn parse(action: &str) -> Option<Actions> {
+        let action = action.trim();
+
+        if let Ok(bits) = action.parse::<u32>() {
+            return Actions::from_bits(bits);
+        }
+
+        let names: Vec<String> = if action.starts_with('[') {
+            serde_json::from_str(action).ok()?
+        } else {
+            action.split('|').map(str::to_string).collect()
+        };
+
+        names.iter().try_fold(Actions::empty(), |actions, name| {
+            Some(actions | Actions::from_name(name.trim())?)
+        })
+    }
+}
+
+/// Continuous control of a single agent, every component is clamped to [-1, 1] and scaled by
+/// the configured maximum speeds
+#[derive(Deserialize, Default, Clone, Copy, Debug)]
+#[serde(default)]
+pub(crate) struct ContinuousActions {
+    /// Strafe, positive to the right
+    pub(crate) move_x: f32,
+    /// Positive forward
+    pub(crate) move_z: f32,
+    /// Positive turns left
+    pub(crate) turn: f32,
+    /// Shoots when positive
+    pub(crate) fire: f32,
+    /// Starts reloading when positive
+    pub(crate) reload: f32,
+    /// Switches to the next weapon when it turns positive, see `ContinuousActions::press`
+    pub(crate) next_weapon: f32,
+}
+
+impl ContinuousActions {
+    /// Parse a JSON object (`{"move_x": 0.5, "turn": -1.0}`, missing fields are 0) or a JSON
+    /// array in `[move_x, move_z, turn, fire, reload, next_weapon]` order, the last two may
+    /// be left out
+    pub(crate) fn parse(action: &str) -> Option<ContinuousActions> {
+        let action = action.trim();
+
+        let actions = if action.starts_with('[') {
+            let values: Vec<f32> = serde_json::from_str(action).ok()?;
+            if !(4..=6).contains(&values.len()) {
+                return None;
+            }
+            ContinuousActions {
+                move_x: values[0],
+                move_z: values[1],
+                turn: values[2],
+                fire: values[3],
+                reload: values.get(4).copied().unwrap_or_default(),
+                next_weapon: values.get(5).copied().unwrap_or_default(),
+            }
+        } else {
+            serde_json::from_str(action).ok()?
+        };
+
+        Some(actions.clamped())
+    }
+
+    /// Keeps `next_weapon` only on the step it turns positive, so holding it switches weapons
+    /// once. `held` is whether it was positive on the agent's previous step.
+    pub(crate) fn press(&mut self, held: &mut bool) {
+        let pressed = self.next_weapon > 0.0;
+        if *held {
+            self.next_weapon = 0.0;
+        }
+        *held = pressed;
+    }
+
+    fn clamped(self) -> ContinuousActions {
+        let clamp = |v: f32| {
+            if v.is_finite() {
+                v.clamp(-1.0, 1.0)
+            } else {
+                0.0
+            }
+        };
+
+        ContinuousActions {
+            move_x: clamp(self.move_x),
+            move_z: clamp(self.move_z),
+            turn: clamp(self.turn),
+            fire: clamp(self.fire),
+            reload: clamp(self.reload),
+            next_weapon: clamp(self.next_weapon),
+        }
+    }
+}
+
+/// Action of a single agent in either of the action spaces
+#[derive(Clone, Debug)]
+pub(crate) enum AgentAction {
+    Discrete(Actions),
+    Continuous(ContinuousActions),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn discrete_forms() {
+        let forward_shoot = Actions::FORWARD | Actions::SHOOT;
+        assert_eq!(Actions::parse("FORWARD"), Some(Actions::FORWARD));
+        assert_eq!(
+            Actions::parse(" FORWARD | SHOOT "),
+            Some(forward_shoot.clone())
+        );
+        assert_eq!(
+            Actions::parse(r#"["FORWARD", "SHOOT"]"#),
+            Some(forward_shoot.clone())
+        );
+        assert_eq!(Actions::parse("130"), Some(forward_shoot));
+        assert_eq!(Actions::parse("0"), Some(Actions::empty()));
+    }
+
+    #[test]
+    fn invalid_discrete_actions_are_idle() {
+        for action in [
+            "",
+            "JUMP",
+            "FORWARD|JUMP",
+            "[\"FORWARD\"",
+            "[1]",
+            "1024",
+            "-1",
+        ] {
+            assert_eq!(Actions::parse(action), None, "{action:?}");
+        }
+    }
+
+    #[test]
+    fn continuous_object() {
+        let action = ContinuousActions::parse(r#"{"move_x": 0.5, "turn": -3.0}"#).unwrap();
+        assert_eq!(action.move_x, 0.5);
+        assert_eq!(action.move_z, 0.0);
+        assert_eq!(action.turn, -1.0);
+        assert_eq!(action.next_weapon, 0.0);
+    }
+
+    #[test]
+    fn continuous_array() {
+        let short = ContinuousActions::parse("[0.1, 0.2, 0.3, 1.0]").unwrap();
+        assert_eq!((short.move_x, short.fire, short.reload), (0.1, 1.0, 0.0));
+
+        let full = ContinuousActions::parse("[0, 0, 0, 0, 1, 2]").unwrap();
+        assert_eq!((full.reload, full.next_weapon), (1.0, 1.0));
+    }
+
+    #[test]
+    fn invalid_continuous_actions_are_idle() {
+        for action in [
+            "",
+            "FORWARD",
+            "[0, 0, 0]",
+            "[0, 0, 0, 0, 0, 0, 0]",
+            r#"{"move_x": "a"}"#,
+        ] {
+            assert!(ContinuousActions::parse(action).is_none(), "{action:?}");
+        }
+    }
+
+    #[test]
+    fn next_weapon_switches_once_while_held() {
+        let mut held = false;
+        let steps: Vec<f32> = [1.0, 1.0, 0.5, 0.0, 1.0]
+            .into_iter()
+            .map(|next_weapon| {
+                let mut action = ContinuousActions {
+                    next_weapon,
+                    ..Default::default()
+                };
+                action.press(&mut held);
+                action.next_weapon
+            })
+            .collect();
+        assert_eq!(steps, vec![1.0, 0.0, 0.0, 0.0, 1.0]);
     }
 }
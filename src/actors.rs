@@ -1,37 +1,78 @@
+use std::collections::HashSet;
+
 use bevy::{
-    core_pipeline::clear_color::ClearColorConfig, prelude::*, render::camera::RenderTarget,
+    core_pipeline::clear_color::ClearColorConfig,
+    prelude::*,
+    render::camera::{RenderTarget, Viewport},
 };
 
 use bevy_mod_raycast::{RaycastMesh, RaycastSource};
 use bevy_rapier3d::prelude::*;
 use bevy_rl::*;
 
-use names::Generator;
-use rand::{prelude::SliceRandom, thread_rng, Rng};
+use names::{ADJECTIVES, NOUNS};
+use rand::{prelude::SliceRandom, Rng};
 use serde::Serialize;
 
 use crate::gym::EnvironmentState;
-use crate::{actions::*, game::*, level::*};
+use crate::{
+    actions::*, config::*, events::*, game::*, level::*, modes::*, observations::*, render::*,
+    teams::*, weapons::*,
+};
+
+/// Radius of the actor collider and of its body model, which every weapon hits alike
+pub(crate) const ACTOR_RADIUS: f32 = 1.0;
+
+/// Whether a hit at `hit` on the body centered at `center` is in the critical zone
+pub(crate) fn is_critical_hit(critical: Option<&CriticalConfig>, hit: Vec3, center: Vec3) -> bool {
+    critical.map_or(false, |critical| {
+        (hit.y - center.y) / ACTOR_RADIUS >= critical.min_height
+    })
+}
 
 // Components
 
+/// Stable index of an agent, assigned at spawn. Actions, rewards, terminations, render
+/// images and every per-agent list in the state response are indexed by it.
+#[derive(Component, Serialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(transparent)]
+pub struct AgentId(pub usize);
+
 #[derive(Component, Clone, Serialize)]
 pub struct Actor {
+    /// Same as the actor's `AgentId` component
+    pub id: AgentId,
+    /// Same as the actor's `Team` component, not set in free-for-all
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub team: Option<Team>,
     pub position: (f32, f32),
     pub rotation: f32,
     pub name: String,
     pub health: u16,
 }
 
+/// Time left before a dead actor comes back, see `RespawnConfig`
+#[derive(Component)]
+pub(crate) struct Respawn(pub(crate) Timer);
+
+/// Outcome of the actor's most recent shot
+#[derive(Component, Default, Clone, Copy)]
+pub(crate) struct LastShot {
+    pub(crate) hit: bool,
+}
+
 // Bundles
 
 #[derive(Bundle)]
 pub(crate) struct ActorBundle {
     collider: Collider,
     actor: Actor,
+    agent_id: AgentId,
     rigid_body: RigidBody,
     locked_axes: LockedAxes,
     velocity: Velocity,
+    last_shot: LastShot,
+    arsenal: Arsenal,
     #[bundle]
     spacial_bundle: SpatialBundle,
 }
@@ -44,15 +85,83 @@ struct ActorWeaponBundle {
 }
 
 // Constructors
-fn new_agent_bundle(game_map: GameMap, actor_name: String) -> ActorBundle {
-    let mut rng = thread_rng();
-    let pos = game_map.empty_space.choose(&mut rng).unwrap();
 
+/// Same `adjective-noun` names as `names::Generator`, but drawn from the episode RNG
+fn new_agent_name(rng: &mut impl Rng, taken: &[Actor]) -> String {
+    loop {
+        let name = format!(
+            "{}-{}",
+            ADJECTIVES.choose(rng).unwrap(),
+            NOUNS.choose(rng).unwrap()
+        );
+        if taken.iter().all(|a| a.name != name) {
+            return name;
+        }
+    }
+}
+
+/// Spawn cell of a new life for an actor of `team`. Cells closer than `min_distance` to a
+/// living actor in `others` or, with `avoid_line_of_sight`, visible from an enemy among them
+/// are rejected; when every cell is rejected line of sight and then distance are ignored.
+fn spawn_point(
+    game_map: &GameMap,
+    spawn: &SpawnConfig,
+    walls: &HashSet<(i32, i32)>,
+    others: &[(Vec3, Option<Team>)],
+    team: Option<Team>,
+    rng: &mut impl Rng,
+) -> (usize, usize) {
+    let position = |(x, z): (usize, usize)| Vec3::new(x as f32, 1.0, z as f32);
+    // Without teams everybody is an enemy
+    let enemies: Vec<Vec3> = others
+        .iter()
+        .filter(|(_, other)| team.is_none() || *other != team)
+        .map(|(p, _)| *p)
+        .collect();
+
+    let cells = game_map.spawn_cells();
+    let far: Vec<(usize, usize)> = cells
+        .iter()
+        .filter(|cell| {
+            others
+                .iter()
+                .all(|(p, _)| p.distance(position(**cell)) >= spawn.min_distance)
+        })
+        .copied()
+        .collect();
+    let hidden: Vec<(usize, usize)> = far
+        .iter()
+        .filter(|cell| {
+            !spawn.avoid_line_of_sight
+                || enemies
+                    .iter()
+                    .all(|p| !line_of_sight(walls, *p, position(**cell)))
+        })
+        .copied()
+        .collect();
+
+    [hidden, far, cells]
+        .iter()
+        .find_map(|candidates| candidates.choose(rng).copied())
+        .unwrap()
+}
+
+fn new_agent_bundle(
+    cell: (usize, usize),
+    id: AgentId,
+    team: Option<Team>,
+    actor_name: String,
+    health: u16,
+    arsenal: Arsenal,
+    rng: &mut impl Rng,
+) -> ActorBundle {
     let actor = Actor {
-        position: (pos.0 as f32, pos.1 as f32),
+        id,
+        team,
+        position: (cell.0 as f32, cell.1 as f32),
         rotation: rng.gen_range(0.0..std::f32::consts::PI * 2.0),
         name: actor_name,
-        health: 100,
+        health,
     };
 
     ActorBundle {
@@ -66,19 +175,26 @@ fn new_agent_bundle(game_map: GameMap, actor_name: String) -> ActorBundle {
             ..Default::default()
         },
         velocity: Velocity { ..default() },
-        collider: Collider::ball(1.0),
+        last_shot: LastShot::default(),
+        arsenal,
+        collider: Collider::ball(ACTOR_RADIUS),
         rigid_body: RigidBody::Dynamic,
         actor,
+        agent_id: id,
         locked_axes: (LockedAxes::ROTATION_LOCKED_X | LockedAxes::ROTATION_LOCKED_Z),
     }
 }
 
-fn new_agent_camera_bundle(render_target: RenderTarget) -> ActorWeaponBundle {
+fn new_agent_camera_bundle(
+    render_target: RenderTarget,
+    viewport: Option<Viewport>,
+) -> ActorWeaponBundle {
     ActorWeaponBundle {
         camera_bundle: Camera3dBundle {
             camera: Camera {
                 // order: -1,
                 target: render_target,
+                viewport,
                 ..default()
             },
             camera_3d: Camera3d {
@@ -96,43 +212,324 @@ pub(crate) fn spawn_computer_actors(
     mut commands: Commands,
     game_map: Res<GameMap>,
     ai_gym_state: Res<AIGymState<Actions, EnvironmentState>>,
+    run_mode: Res<RunMode>,
+    config: Res<GameConfig>,
+    mut rng: ResMut<GameRng>,
 
+    pass_materials: Option<Res<PassMaterials>>,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
 ) {
     let mut ai_gym_state = ai_gym_state.lock().unwrap();
     let ai_gym_settings = ai_gym_state.settings.clone();
-    let material = materials.add(Color::RED.into());
+    // Free-for-all agents all get the first team color
+    let team_materials: Vec<Handle<StandardMaterial>> = TEAM_COLORS
+        .iter()
+        .map(|color| materials.add((*color).into()))
+        .collect();
     let mesh = meshes.add(Mesh::from(shape::UVSphere {
         sectors: 128,
         stacks: 64,
         ..default()
     }));
 
+    let passes = config.observations.visual_passes();
+
+    let walls = game_map.wall_set();
     let mut actors: Vec<Actor> = Vec::new();
-    for i in 0..ai_gym_settings.num_agents {
-        let agent_bundle = new_agent_bundle(game_map.clone(), Generator::default().next().unwrap());
+    let mut occupied: Vec<(Vec3, Option<Team>)> = Vec::new();
+    for i in 0..ai_gym_settings.num_agents as usize {
+        let id = AgentId(i);
+        let team = config.teams.as_ref().map(|teams| Team(teams.team_of(i)));
+        let material = team_materials[team.map_or(0, |t| t.0) % TEAM_COLORS.len()].clone();
+        let name = new_agent_name(&mut rng.rng, &actors);
+        let cell = spawn_point(
+            &game_map,
+            &config.spawn,
+            &walls,
+            &occupied,
+            team,
+            &mut rng.rng,
+        );
+        occupied.push((Vec3::new(cell.0 as f32, 1.0, cell.1 as f32), team));
+        let agent_bundle = new_agent_bundle(
+            cell,
+            id,
+            team,
+            name,
+            config.starting_health,
+            Arsenal::new(&config.weapons),
+            &mut rng.rng,
+        );
+        let is_player = *run_mode == RunMode::Play && i == 0;
 
         actors.push(agent_bundle.actor.clone());
-        commands.spawn(agent_bundle).with_children(|cell| {
+        let mut agent = commands.spawn(agent_bundle);
+        if is_player {
+            agent.insert(PlayerControlled);
+        }
+        if let Some(team) = team {
+            agent.insert(team);
+        }
+        if let Some(lidar) = &config.observations.lidar {
+            agent.insert(LidarScan::new(lidar));
+        }
+        agent.with_children(|cell| {
             // Agent model
             cell.spawn(PbrBundle {
                 mesh: mesh.clone(),
                 material: material.clone(),
-                transform: Transform::from_scale(Vec3::splat(0.33)),
+                transform: Transform::from_scale(Vec3::splat(ACTOR_RADIUS)),
                 ..default()
             })
             .insert(RaycastMesh::<RaycastMarker>::default());
-            // Camera
+            if let Some(pass_materials) = &pass_materials {
+                pass_materials.spawn_agent_copies(
+                    cell,
+                    &mesh,
+                    Transform::from_scale(Vec3::splat(ACTOR_RADIUS)),
+                    i,
+                    ai_gym_settings.num_agents as usize,
+                );
+            }
+            // Camera, extra passes are rendered next to it in the same image
+            let render_target =
+                RenderTarget::Image(ai_gym_state.render_image_handles[id.0].clone());
             let agent_camera_bundle: ActorWeaponBundle = new_agent_camera_bundle(
-                RenderTarget::Image(ai_gym_state.render_image_handles[i as usize].clone()),
+                render_target.clone(),
+                (passes.len() > 1).then(|| pass_viewport(0, config.width, config.height)),
             );
             cell.spawn(agent_camera_bundle);
+            for (index, pass) in passes.iter().enumerate().skip(1) {
+                cell.spawn(new_pass_camera_bundle(
+                    *pass,
+                    render_target.clone(),
+                    pass_viewport(index, config.width, config.height),
+                    index as isize,
+                    i,
+                ));
+            }
+            // Lidar
+            if let Some(lidar) = &config.observations.lidar {
+                for index in 0..lidar.rays as usize {
+                    cell.spawn((
+                        TransformBundle::from_transform(Transform::from_rotation(
+                            Quat::from_rotation_y(lidar.ray_angle(index)),
+                        )),
+                        RaycastSource::<RaycastMarker>::new_transform_empty(),
+                        LidarRay { index },
+                    ));
+                }
+            }
+            // First-person view for the human player
+            if is_player {
+                cell.spawn(Camera3dBundle {
+                    camera: Camera {
+                        order: 1,
+                        ..default()
+                    },
+                    ..default()
+                });
+            }
         });
     }
     let env_state = EnvironmentState {
         map: game_map.clone(),
         actors,
+        seed: rng.seed,
+        ..default()
     };
     ai_gym_state.set_env_state(env_state);
 }
+
+/// Starts the respawn timer of every actor killed this frame
+pub(crate) fn schedule_respawns(
+    mut commands: Commands,
+    config: Res<GameConfig>,
+    mut event_death: EventReader<EventDeath>,
+    actors: Query<(Entity, &AgentId)>,
+) {
+    let Some(respawn) = &config.respawn else {
+        return;
+    };
+
+    for death in event_death.iter() {
+        if let Some((entity, _)) = actors.iter().find(|(_, id)| **id == death.victim) {
+            commands
+                .entity(entity)
+                .insert(Respawn(Timer::from_seconds(respawn.delay, TimerMode::Once)));
+        }
+    }
+}
+
+/// Brings dead actors back with full health and ammunition away from living actors. Their
+/// termination flag is cleared, so the API sees a new life starting.
+pub(crate) fn respawn_actors(
+    mut commands: Commands,
+    time: Res<Time>,
+    config: Res<GameConfig>,
+    game_map: Res<GameMap>,
+    mut rng: ResMut<GameRng>,
+    ai_gym_state: Res<AIGymState<Actions, EnvironmentState>>,
+    mut respawning: Query<(
+        Entity,
+        &mut Respawn,
+        &mut Actor,
+        &AgentId,
+        &mut Transform,
+        &mut Velocity,
+        &mut Arsenal,
+        &mut LastShot,
+    )>,
+    living: Query<(&Transform, &Actor), Without<Respawn>>,
+    walls: Query<&Wall>,
+) {
+    let walls = wall_cells(walls.iter());
+    let mut occupied: Vec<(Vec3, Option<Team>)> = living
+        .iter()
+        .filter(|(_, actor)| actor.health > 0)
+        .map(|(transform, actor)| (transform.translation, actor.team))
+        .collect();
+
+    for (
+        entity,
+        mut respawn,
+        mut actor,
+        id,
+        mut transform,
+        mut velocity,
+        mut arsenal,
+        mut last_shot,
+    ) in respawning.iter_mut()
+    {
+        if !respawn.0.tick(time.delta()).finished() {
+            continue;
+        }
+
+        let (x, z) = spawn_point(
+            &game_map,
+            &config.spawn,
+            &walls,
+            &occupied,
+            actor.team,
+            &mut rng.rng,
+        );
+        actor.position = (x as f32, z as f32);
+        actor.rotation = rng.rng.gen_range(0.0..std::f32::consts::PI * 2.0);
+        actor.health = config.starting_health;
+        *transform = Transform {
+            translation: Vec3::new(actor.position.0, 1.0, actor.position.1),
+            rotation: Quat::from_rotation_y(actor.rotation),
+            ..default()
+        };
+        *velocity = Velocity::zero();
+        *arsenal = Arsenal::new(&config.weapons);
+        *last_shot = LastShot::default();
+        occupied.push((transform.translation, actor.team));
+
+        commands.entity(entity).remove::<Respawn>();
+        ai_gym_state.lock().unwrap().set_terminated(id.0, false);
+    }
+}
+
+/// Agent copies of the extra passes ignore the hidden agent, so they are hidden with its death
+/// and shown again when it respawns
+pub(crate) fn sync_agent_copies(
+    actors: Query<&Actor>,
+    mut copies: Query<(&Parent, &mut Visibility), With<AgentCopy>>,
+) {
+    for (parent, mut visibility) in copies.iter_mut() {
+        let alive = actors
+            .get(parent.get())
+            .map_or(false, |actor| actor.health > 0);
+        let wanted = if alive {
+            Visibility::Visible
+        } else {
+            Visibility::Hidden
+        };
+        if *visibility != wanted {
+            *visibility = wanted;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    /// Two rooms of two cells on row z = 2, split by the wall cell (6, 2)
+    fn rooms() -> GameMap {
+        GameMap::from_text("#######\n#..#..#\n#######\n").unwrap()
+    }
+
+    fn at(x: f32) -> Vec3 {
+        Vec3::new(x, 1.0, 2.0)
+    }
+
+    /// Cells picked over many draws
+    fn spawns(
+        spawn: &SpawnConfig,
+        others: &[(Vec3, Option<Team>)],
+        team: Option<Team>,
+    ) -> BTreeSet<(usize, usize)> {
+        let game_map = rooms();
+        let walls = game_map.wall_set();
+        (0..64)
+            .map(|seed| {
+                let mut rng = StdRng::seed_from_u64(seed);
+                spawn_point(&game_map, spawn, &walls, others, team, &mut rng)
+            })
+            .collect()
+    }
+
+    fn spawn_config(min_distance: f32, avoid_line_of_sight: bool) -> SpawnConfig {
+        SpawnConfig {
+            min_distance,
+            avoid_line_of_sight,
+        }
+    }
+
+    #[test]
+    fn keeps_min_distance() {
+        let others = [(at(2.0), None)];
+        assert_eq!(
+            spawns(&spawn_config(5.0, false), &others, None),
+            BTreeSet::from([(8, 2), (10, 2)])
+        );
+    }
+
+    #[test]
+    fn ignores_distance_when_no_cell_is_far_enough() {
+        let others = [(at(2.0), None)];
+        assert_eq!(spawns(&spawn_config(100.0, false), &others, None).len(), 4);
+    }
+
+    #[test]
+    fn hides_from_enemies_only() {
+        let enemy = [(at(2.0), Some(Team(1)))];
+        assert_eq!(
+            spawns(&spawn_config(0.0, true), &enemy, Some(Team(0))),
+            BTreeSet::from([(8, 2), (10, 2)])
+        );
+
+        let teammate = [(at(2.0), Some(Team(0)))];
+        assert_eq!(
+            spawns(&spawn_config(0.0, true), &teammate, Some(Team(0))).len(),
+            4
+        );
+    }
+
+    #[test]
+    fn ignores_line_of_sight_before_distance() {
+        // Every cell is seen from one of the rooms, the ones next to an enemy stay too close
+        let enemies = [(at(2.0), None), (at(10.0), None)];
+        assert_eq!(
+            spawns(&spawn_config(1.0, true), &enemies, None),
+            BTreeSet::from([(4, 2), (8, 2)])
+        );
+    }
+}
@@ -0,0 +1,1115 @@
+use std::{f32::consts::FRAC_PI_2, fmt, fs, io, path::Path, path::PathBuf};
+
+use bevy::prelude::*;
+use bevy_rl::AIGymSettings;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    game_mode::{game_mode, selected_modes},
+    level::{GameMap, MAP_SIZE},
+    mapgen,
+    modes::RunMode,
+    render::{VisualPass, MAX_SEGMENTATION_AGENTS},
+};
+
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ActionSpace {
+    /// `Actions` bitflags, see `Actions::parse`
+    #[default]
+    Discrete,
+    /// `{move_x, move_z, turn, fire}` floats, see `ContinuousActions::parse`
+    Continuous,
+}
+
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum GeneratorKind {
+    /// Recursive-backtracker maze with loops
+    #[default]
+    Maze,
+    /// Rooms joined by corridors
+    Dungeon,
+    /// Open floor with scattered cover
+    Arena,
+}
+
+/// Procedurally generated map, see `mapgen::generate`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct GeneratorConfig {
+    pub(crate) kind: GeneratorKind,
+    /// Map width in cells, including the outer wall
+    pub(crate) width: usize,
+    /// Map height in cells, including the outer wall
+    pub(crate) height: usize,
+    /// Share of inner walls kept in a maze, of the map covered by rooms in a dungeon, or of the
+    /// floor covered by cover in an arena; defaults depend on the kind
+    pub(crate) density: Option<f32>,
+    /// Map seed of every episode, the episode seed when not set
+    pub(crate) seed: Option<u64>,
+    /// Generate a new map for every episode from the episode seed
+    pub(crate) regenerate: bool,
+}
+
+impl Default for GeneratorConfig {
+    fn default() -> Self {
+        Self {
+            kind: GeneratorKind::Maze,
+            width: 48,
+            height: 48,
+            density: None,
+            seed: None,
+            regenerate: false,
+        }
+    }
+}
+
+impl GeneratorConfig {
+    /// Seed of the map generated for an episode, at startup as with `regenerate`
+    pub(crate) fn map_seed(&self, episode_seed: u64) -> u64 {
+        self.seed.unwrap_or(episode_seed)
+    }
+
+    pub(crate) fn density(&self) -> f32 {
+        self.density.unwrap_or(match self.kind {
+            GeneratorKind::Maze => 0.9,
+            GeneratorKind::Dungeon => 0.4,
+            GeneratorKind::Arena => 0.15,
+        })
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |msg: String| -> Result<(), ConfigError> {
+            Err(ConfigError::Invalid(format!("generator: {msg}")))
+        };
+
+        let sizes = 5..=MAP_SIZE / 2;
+        if !sizes.contains(&self.width) || !sizes.contains(&self.height) {
+            return invalid(format!(
+                "width and height must be in [{}, {}], got {} and {}",
+                sizes.start(),
+                sizes.end(),
+                self.width,
+                self.height
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.density()) {
+            return invalid(format!("density must be in [0, 1], got {}", self.density()));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum PoolStrategy {
+    /// Every map is equally likely
+    #[default]
+    Uniform,
+    /// Maps in the order they are listed, starting over after the last one
+    RoundRobin,
+    /// Maps are picked in proportion to their `weight`
+    Weighted,
+}
+
+/// Map of a `MapPoolConfig`, read from `path` or made by `generator`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct PoolMapConfig {
+    /// Name reported as `map_id`, the file path or generator kind and index when not set
+    pub(crate) id: Option<String>,
+    /// JSON or `.txt` text map file
+    pub(crate) path: Option<PathBuf>,
+    /// Generated from the episode seed, or from its own `seed`, every time it is picked
+    pub(crate) generator: Option<GeneratorConfig>,
+    /// Relative chance of being picked with the `weighted` strategy
+    pub(crate) weight: f32,
+}
+
+impl Default for PoolMapConfig {
+    fn default() -> Self {
+        Self {
+            id: None,
+            path: None,
+            generator: None,
+            weight: 1.0,
+        }
+    }
+}
+
+/// Maps to pick a new one from for every episode, see `map_pool::pick_pool_map`
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct MapPoolConfig {
+    pub(crate) strategy: PoolStrategy,
+    pub(crate) maps: Vec<PoolMapConfig>,
+}
+
+/// Fan of rays cast from every agent, see `LidarScan`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct LidarConfig {
+    pub(crate) rays: u32,
+    /// Field of view in degrees, centered on the agent's heading
+    pub(crate) fov: f32,
+    pub(crate) max_range: f32,
+}
+
+impl Default for LidarConfig {
+    fn default() -> Self {
+        Self {
+            rays: 16,
+            fov: 90.0,
+            max_range: 50.0,
+        }
+    }
+}
+
+impl LidarConfig {
+    /// Yaw of ray `index` relative to the agent's heading; rays are ordered left to right
+    pub(crate) fn ray_angle(&self, index: usize) -> f32 {
+        let fraction = (index as f32 + 0.5) / self.rays as f32;
+        self.fov.to_radians() * (0.5 - fraction)
+    }
+}
+
+/// Egocentric top-down grid around every agent, see `OccupancyGrid`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct OccupancyConfig {
+    /// Cells per side, the agent sits in the middle
+    pub(crate) size: u32,
+    /// Cell side in world units
+    pub(crate) cell_size: f32,
+    /// Seconds of round time a shot stays visible in the shots channel
+    pub(crate) shot_ttl: f32,
+}
+
+impl Default for OccupancyConfig {
+    fn default() -> Self {
+        Self {
+            size: 32,
+            cell_size: 2.0,
+            shot_ttl: 1.0,
+        }
+    }
+}
+
+/// Depth pass of the agent cameras
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct DepthConfig {
+    /// Distance mapped to white, anything further is clamped
+    pub(crate) max_depth: f32,
+}
+
+impl Default for DepthConfig {
+    fn default() -> Self {
+        Self { max_depth: 100.0 }
+    }
+}
+
+/// Observation modalities returned in the state response besides the camera pixels
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ObservationConfig {
+    /// Per-agent health, velocity, heading, round time and last shot outcome
+    pub(crate) proprioception: bool,
+    pub(crate) lidar: Option<LidarConfig>,
+    pub(crate) occupancy: Option<OccupancyConfig>,
+    /// Render a depth pass next to the color image of every agent camera
+    pub(crate) depth: Option<DepthConfig>,
+    /// Render a semantic segmentation pass next to the color image of every agent camera
+    pub(crate) segmentation: bool,
+}
+
+impl ObservationConfig {
+    /// Passes rendered into each agent's image, left to right
+    pub(crate) fn visual_passes(&self) -> Vec<VisualPass> {
+        let mut passes = vec![VisualPass::Color];
+        if self.depth.is_some() {
+            passes.push(VisualPass::Depth);
+        }
+        if self.segmentation {
+            passes.push(VisualPass::Segmentation);
+        }
+        passes
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum WeaponKind {
+    /// Single instant ray from the agent camera
+    #[default]
+    Hitscan,
+    /// `pellets` instant rays spread over `spread` degrees
+    Shotgun,
+    /// Physics-simulated rocket exploding on impact with `splash_radius`
+    Projectile,
+}
+
+/// An entry of the weapon catalog every actor spawns with, see `Arsenal`.
+///
+/// Fields that don't apply to `kind` are ignored.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct WeaponConfig {
+    pub(crate) name: String,
+    pub(crate) kind: WeaponKind,
+    /// Health removed by a hit, per pellet for shotguns and at the center of an explosion
+    pub(crate) damage: u16,
+    /// Seconds between two shots
+    pub(crate) fire_interval: f32,
+    pub(crate) magazine_size: u32,
+    /// Rounds carried outside the magazine at spawn
+    pub(crate) reserve_ammo: u32,
+    /// Seconds it takes to refill the magazine
+    pub(crate) reload_time: f32,
+    pub(crate) pellets: u32,
+    /// Horizontal spread of the pellets in degrees
+    pub(crate) spread: f32,
+    /// Pellet range in world units
+    pub(crate) range: f32,
+    /// Projectile speed in units per second
+    pub(crate) projectile_speed: f32,
+    /// Explosion radius, damage falls off linearly to 0 at the edge
+    pub(crate) splash_radius: f32,
+    /// Seconds before a projectile that hit nothing explodes
+    pub(crate) lifetime: f32,
+}
+
+impl Default for WeaponConfig {
+    fn default() -> Self {
+        Self {
+            name: "rifle".to_string(),
+            kind: WeaponKind::Hitscan,
+            damage: 100,
+            fire_interval: 0.25,
+            magazine_size: 10,
+            reserve_ammo: 90,
+            reload_time: 1.5,
+            pellets: 8,
+            spread: 15.0,
+            range: 30.0,
+            projectile_speed: 30.0,
+            splash_radius: 5.0,
+            lifetime: 3.0,
+        }
+    }
+}
+
+/// Rifle, shotgun and rocket launcher, in that order
+fn default_weapons() -> Vec<WeaponConfig> {
+    vec![
+        WeaponConfig::default(),
+        WeaponConfig {
+            name: "shotgun".to_string(),
+            kind: WeaponKind::Shotgun,
+            damage: 25,
+            fire_interval: 1.0,
+            magazine_size: 6,
+            reserve_ammo: 30,
+            reload_time: 2.5,
+            ..default()
+        },
+        WeaponConfig {
+            name: "rocket".to_string(),
+            kind: WeaponKind::Projectile,
+            damage: 150,
+            fire_interval: 1.5,
+            magazine_size: 1,
+            reserve_ammo: 10,
+            reload_time: 2.0,
+            ..default()
+        },
+    ]
+}
+
+/// Linear damage falloff with the distance between shooter and hit point
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct FalloffConfig {
+    /// Hits closer than this deal full damage
+    pub(crate) start: f32,
+    /// Hits further than this deal `min_fraction` of the damage
+    pub(crate) end: f32,
+    pub(crate) min_fraction: f32,
+}
+
+impl Default for FalloffConfig {
+    fn default() -> Self {
+        Self {
+            start: 10.0,
+            end: 50.0,
+            min_fraction: 0.25,
+        }
+    }
+}
+
+/// Critical hits on the upper part of the agent body, the same for every weapon
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct CriticalConfig {
+    /// Hits this high above the body center are critical, as a fraction of `ACTOR_RADIUS`
+    pub(crate) min_height: f32,
+    pub(crate) multiplier: f32,
+}
+
+impl Default for CriticalConfig {
+    fn default() -> Self {
+        Self {
+            min_height: 0.5,
+            multiplier: 2.0,
+        }
+    }
+}
+
+/// Modifiers applied to the weapon damage on every hit
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct DamageConfig {
+    pub(crate) falloff: Option<FalloffConfig>,
+    pub(crate) critical: Option<CriticalConfig>,
+}
+
+impl WeaponConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        let name = &self.name;
+        let invalid = |msg: String| -> Result<(), ConfigError> {
+            Err(ConfigError::Invalid(format!("weapon {name}: {msg}")))
+        };
+        let non_negative = |value: f32| value.is_finite() && value >= 0.0;
+        let positive = |value: f32| value.is_finite() && value > 0.0;
+
+        if self.damage == 0 {
+            return invalid("damage must be at least 1".to_string());
+        }
+        if !non_negative(self.fire_interval) {
+            return invalid(format!(
+                "fire_interval must not be negative, got {}",
+                self.fire_interval
+            ));
+        }
+        if self.magazine_size == 0 {
+            return invalid("magazine_size must be at least 1".to_string());
+        }
+        if !non_negative(self.reload_time) {
+            return invalid(format!(
+                "reload_time must not be negative, got {}",
+                self.reload_time
+            ));
+        }
+        match self.kind {
+            WeaponKind::Hitscan => {}
+            WeaponKind::Shotgun => {
+                if self.pellets == 0 {
+                    return invalid("pellets must be at least 1".to_string());
+                }
+                if !(0.0..360.0).contains(&self.spread) {
+                    return invalid(format!("spread must be in [0, 360), got {}", self.spread));
+                }
+                if !positive(self.range) {
+                    return invalid(format!("range must be positive, got {}", self.range));
+                }
+            }
+            WeaponKind::Projectile => {
+                if !positive(self.projectile_speed) {
+                    return invalid(format!(
+                        "projectile_speed must be positive, got {}",
+                        self.projectile_speed
+                    ));
+                }
+                if !non_negative(self.splash_radius) {
+                    return invalid(format!(
+                        "splash_radius must not be negative, got {}",
+                        self.splash_radius
+                    ));
+                }
+                if !positive(self.lifetime) {
+                    return invalid(format!("lifetime must be positive, got {}", self.lifetime));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl DamageConfig {
+    /// Health removed by a hit of `base` damage at `distance`, rounded and never below 1
+    pub(crate) fn hit_damage(&self, base: u16, distance: f32, critical: bool) -> u16 {
+        let mut damage = base as f32;
+        if let Some(falloff) = &self.falloff {
+            let t = ((distance - falloff.start) / (falloff.end - falloff.start)).clamp(0.0, 1.0);
+            damage *= 1.0 + (falloff.min_fraction - 1.0) * t;
+        }
+        if let Some(critical_config) = &self.critical {
+            if critical {
+                damage *= critical_config.multiplier;
+            }
+        }
+        damage.round().clamp(1.0, u16::MAX as f32) as u16
+    }
+}
+
+/// Where new lives start, see `GameMap::spawn_zones` for restricting spawns to parts of the map
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct SpawnConfig {
+    /// Minimum distance to every other living actor
+    pub(crate) min_distance: f32,
+    /// Reject cells a living enemy can see
+    pub(crate) avoid_line_of_sight: bool,
+}
+
+impl Default for SpawnConfig {
+    fn default() -> Self {
+        Self {
+            min_distance: 4.0,
+            avoid_line_of_sight: false,
+        }
+    }
+}
+
+/// Team deathmatch, agents are assigned to teams in agent id order
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct TeamConfig {
+    /// Number of agents in every team, adding up to `num_agents`
+    pub(crate) sizes: Vec<u32>,
+    /// Whether hits on teammates deal damage
+    pub(crate) friendly_fire: bool,
+}
+
+impl Default for TeamConfig {
+    fn default() -> Self {
+        Self {
+            sizes: vec![8, 8],
+            friendly_fire: false,
+        }
+    }
+}
+
+impl TeamConfig {
+    pub(crate) fn team_of(&self, index: usize) -> usize {
+        let mut first = 0;
+        for (team, size) in self.sizes.iter().enumerate() {
+            first += *size as usize;
+            if index < first {
+                return team;
+            }
+        }
+        self.sizes.len() - 1
+    }
+}
+
+/// Continuous deathmatch: dead actors come back instead of waiting for the round to end
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct RespawnConfig {
+    /// Seconds between death and respawn, at least the pause interval so that every death is
+    /// reported to the API
+    pub(crate) delay: f32,
+}
+
+impl Default for RespawnConfig {
+    fn default() -> Self {
+        Self { delay: 3.0 }
+    }
+}
+
+/// Capture the flag, needs teams and a flag base per team in the map
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct CtfConfig {
+    /// Captures a team needs to win the round
+    pub(crate) captures_to_win: u32,
+    /// Movement speed of a flag carrier as a fraction of `max_move_speed`
+    pub(crate) carrier_speed: f32,
+    /// Seconds a dropped flag stays on the ground before it returns to its base
+    pub(crate) return_time: f32,
+    /// Radius of the flag sensor
+    pub(crate) radius: f32,
+}
+
+impl Default for CtfConfig {
+    fn default() -> Self {
+        Self {
+            captures_to_win: 3,
+            carrier_speed: 0.7,
+            return_time: 10.0,
+            radius: 1.5,
+        }
+    }
+}
+
+/// King of the hill: agents or teams score by holding a zone
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct KothConfig {
+    /// Radius of the zone in world units
+    pub(crate) radius: f32,
+    /// Points scored per second of holding the zone alone
+    pub(crate) points_per_second: f32,
+    /// Points a team, or an agent in free-for-all, needs to win the round
+    pub(crate) score_to_win: f32,
+    /// Seconds between zone moves, 0 keeps it in place for the whole round
+    pub(crate) relocate_interval: f32,
+}
+
+impl Default for KothConfig {
+    fn default() -> Self {
+        Self {
+            radius: 4.0,
+            points_per_second: 1.0,
+            score_to_win: 30.0,
+            relocate_interval: 20.0,
+        }
+    }
+}
+
+/// Weights of the reward function, see `apply_rewards`.
+///
+/// Every weight is added to the agent's reward as is, use negative weights for penalties.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct RewardConfig {
+    /// Killing another agent
+    pub(crate) kill: f32,
+    /// Per point of health taken from other agents
+    pub(crate) damage_dealt: f32,
+    /// Per point of health lost
+    pub(crate) damage_taken: f32,
+    /// Getting killed
+    pub(crate) death: f32,
+    /// Every simulation tick the agent is alive
+    pub(crate) survival_per_tick: f32,
+    /// Every shot fired, hit or miss
+    pub(crate) shot_fired: f32,
+    /// Every shot that didn't hit another agent
+    pub(crate) missed_shot: f32,
+    /// Every wall block shot down
+    pub(crate) wall_destroyed: f32,
+    /// A teammate killed an enemy
+    pub(crate) team_kill: f32,
+    /// A teammate got killed
+    pub(crate) team_death: f32,
+    /// The agent's team won the round
+    pub(crate) team_win: f32,
+    /// Another team won the round
+    pub(crate) team_loss: f32,
+    /// Bringing the enemy flag to the agent's own base
+    pub(crate) flag_capture: f32,
+    /// Touching the agent's own dropped flag, which sends it back to its base
+    pub(crate) flag_return: f32,
+    /// Per point scored while standing in the hill
+    pub(crate) hill_point: f32,
+}
+
+impl Default for RewardConfig {
+    fn default() -> Self {
+        Self {
+            kill: 10.0,
+            damage_dealt: 0.0,
+            damage_taken: 0.0,
+            death: 0.0,
+            survival_per_tick: 0.0,
+            shot_fired: 0.0,
+            missed_shot: 0.0,
+            wall_destroyed: 0.0,
+            team_kill: 0.0,
+            team_death: 0.0,
+            team_win: 0.0,
+            team_loss: 0.0,
+            flag_capture: 0.0,
+            flag_return: 0.0,
+            hill_point: 0.0,
+        }
+    }
+}
+
+/// Everything an experiment may want to sweep without forking the crate.
+///
+/// Values are resolved as defaults < `--config` file < command line flags.
+#[derive(Resource, Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct GameConfig {
+    pub(crate) mode: RunMode,
+    /// Base seed for spawns, names and bots; episode `n` uses `seed + n`
+    pub(crate) seed: Option<u64>,
+    pub(crate) num_agents: u32,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    /// Seconds between control requests; defaults depend on the mode
+    pub(crate) pause_interval: Option<f32>,
+    /// Round length in seconds
+    pub(crate) round_length: f32,
+    pub(crate) spawn: SpawnConfig,
+    /// Split agents into teams, free-for-all when not set
+    pub(crate) teams: Option<TeamConfig>,
+    /// Play capture the flag instead of deathmatch
+    pub(crate) ctf: Option<CtfConfig>,
+    /// Play king of the hill instead of deathmatch
+    pub(crate) koth: Option<KothConfig>,
+    /// Respawn dead actors, the round then only ends when its time is up or the mode's goal
+    /// is met
+    pub(crate) respawn: Option<RespawnConfig>,
+    pub(crate) starting_health: u16,
+    pub(crate) damage: DamageConfig,
+    /// Weapons every actor spawns with, the first one is selected
+    pub(crate) weapons: Vec<WeaponConfig>,
+    pub(crate) action_space: ActionSpace,
+    /// Movement speed at full throttle, in units per second
+    pub(crate) max_move_speed: f32,
+    /// Turning speed at full throttle, in radians per second
+    pub(crate) max_turn_speed: f32,
+    pub(crate) observations: ObservationConfig,
+    pub(crate) rewards: RewardConfig,
+    /// JSON or `.txt` text map file, the embedded map is used when not set
+    pub(crate) map: Option<PathBuf>,
+    /// Generate the map instead of loading it
+    pub(crate) generator: Option<GeneratorConfig>,
+    /// Pick a map from a pool for every episode
+    pub(crate) map_pool: Option<MapPoolConfig>,
+    /// Number of episodes to run in eval mode
+    pub(crate) episodes: u32,
+    /// Where eval mode writes its results summary
+    pub(crate) output: PathBuf,
+}
+
+impl Default for GameConfig {
+    fn default() -> Self {
+        Self {
+            mode: RunMode::Train,
+            seed: None,
+            num_agents: 16,
+            width: 256,
+            height: 256,
+            pause_interval: None,
+            round_length: 60.0,
+            spawn: SpawnConfig::default(),
+            teams: None,
+            ctf: None,
+            koth: None,
+            respawn: None,
+            starting_health: 100,
+            damage: DamageConfig::default(),
+            weapons: default_weapons(),
+            action_space: ActionSpace::Discrete,
+            max_move_speed: 10.0,
+            max_turn_speed: FRAC_PI_2,
+            observations: ObservationConfig::default(),
+            rewards: RewardConfig::default(),
+            map: None,
+            generator: None,
+            map_pool: None,
+            episodes: 10,
+            output: PathBuf::from("eval_results.json"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, String),
+    UnsupportedFormat(PathBuf),
+    Invalid(String),
+    /// A map that parses but can't be played, with a description of where it came from
+    InvalidMap(String, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, err) => write!(f, "cannot read {}: {err}", path.display()),
+            ConfigError::Parse(path, err) => write!(f, "cannot parse {}: {err}", path.display()),
+            ConfigError::UnsupportedFormat(path) => write!(
+                f,
+                "unsupported file format {}, expected .toml or .json",
+                path.display()
+            ),
+            ConfigError::Invalid(msg) => write!(f, "invalid configuration: {msg}"),
+            ConfigError::InvalidMap(source, msg) => write!(f, "invalid {source}: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Deserialize a `.toml` or `.json` file, picking the format by extension
+pub(crate) fn read_file<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T, ConfigError> {
+    let contents = fs::read_to_string(path).map_err(|err| ConfigError::Io(path.into(), err))?;
+
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("toml") => toml::from_str(&contents)
+            .map_err(|err| ConfigError::Parse(path.into(), err.to_string())),
+        Some("json") => serde_json::from_str(&contents)
+            .map_err(|err| ConfigError::Parse(path.into(), err.to_string())),
+        _ => Err(ConfigError::UnsupportedFormat(path.into())),
+    }
+}
+
+impl GameConfig {
+    pub(crate) fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        read_file(path)
+    }
+
+    pub(crate) fn pause_interval(&self) -> f32 {
+        // Local modes resolve control immediately, pause more often for responsive input
+        self.pause_interval
+            .unwrap_or(if self.mode.is_local() { 0.05 } else { 0.1 })
+    }
+
+    pub(crate) fn gym_settings(&self) -> AIGymSettings {
+        // Extra passes are rendered next to the color image
+        AIGymSettings {
+            width: self.width * self.observations.visual_passes().len() as u32,
+            height: self.height,
+            num_agents: self.num_agents,
+            pause_interval: self.pause_interval(),
+            render_to_buffer: true,
+        }
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |msg: String| -> Result<(), ConfigError> { Err(ConfigError::Invalid(msg)) };
+
+        if self.num_agents == 0 {
+            return invalid("num_agents must be at least 1".to_string());
+        }
+        if self.width == 0 || self.height == 0 {
+            return invalid(format!(
+                "render resolution must be positive, got {}x{}",
+                self.width, self.height
+            ));
+        }
+        let pause_interval = self.pause_interval();
+        if !pause_interval.is_finite() || pause_interval <= 0.0 {
+            return invalid(format!(
+                "pause_interval must be a positive number of seconds, got {pause_interval}"
+            ));
+        }
+        if !self.round_length.is_finite() || self.round_length <= 0.0 {
+            return invalid(format!(
+                "round_length must be a positive number of seconds, got {}",
+                self.round_length
+            ));
+        }
+        if !self.spawn.min_distance.is_finite() || self.spawn.min_distance < 0.0 {
+            return invalid(format!(
+                "spawn min_distance must not be negative, got {}",
+                self.spawn.min_distance
+            ));
+        }
+        if let Some(teams) = &self.teams {
+            if teams.sizes.len() < 2 || teams.sizes.contains(&0) {
+                return invalid(format!(
+                    "teams need at least two non-empty teams, got sizes {:?}",
+                    teams.sizes
+                ));
+            }
+            if teams.sizes.iter().sum::<u32>() != self.num_agents {
+                return invalid(format!(
+                    "team sizes {:?} must add up to num_agents {}",
+                    teams.sizes, self.num_agents
+                ));
+            }
+        }
+        if let [first, second, ..] = selected_modes(self).as_slice() {
+            return invalid(format!(
+                "{} and {} can't be combined",
+                first.name(),
+                second.name()
+            ));
+        }
+        game_mode(self)
+            .validate(self)
+            .map_err(ConfigError::Invalid)?;
+        if let Some(respawn) = &self.respawn {
+            if !respawn.delay.is_finite() || respawn.delay < 0.0 {
+                return invalid(format!(
+                    "respawn delay must not be negative, got {}",
+                    respawn.delay
+                ));
+            }
+            // A shorter delay could respawn the agent before a pause reports its death
+            if respawn.delay < self.pause_interval() {
+                return invalid(format!(
+                    "respawn delay {} must be at least the pause interval {}",
+                    respawn.delay,
+                    self.pause_interval()
+                ));
+            }
+        }
+        if self.starting_health == 0 {
+            return invalid("starting_health must be at least 1".to_string());
+        }
+        if self.weapons.is_empty() {
+            return invalid("at least one weapon is required".to_string());
+        }
+        for weapon in &self.weapons {
+            weapon.validate()?;
+        }
+        if let Some(falloff) = &self.damage.falloff {
+            if !(falloff.start >= 0.0 && falloff.end > falloff.start && falloff.end.is_finite()) {
+                return invalid(format!(
+                    "damage falloff needs 0 <= start < end, got {} and {}",
+                    falloff.start, falloff.end
+                ));
+            }
+            if !(0.0..=1.0).contains(&falloff.min_fraction) {
+                return invalid(format!(
+                    "damage falloff min_fraction must be in [0, 1], got {}",
+                    falloff.min_fraction
+                ));
+            }
+        }
+        if let Some(critical) = &self.damage.critical {
+            if !critical.min_height.is_finite() {
+                return invalid(format!(
+                    "critical min_height must be finite, got {}",
+                    critical.min_height
+                ));
+            }
+            if !critical.multiplier.is_finite() || critical.multiplier < 1.0 {
+                return invalid(format!(
+                    "critical multiplier must be at least 1, got {}",
+                    critical.multiplier
+                ));
+            }
+        }
+        if !self.max_move_speed.is_finite() || self.max_move_speed <= 0.0 {
+            return invalid(format!(
+                "max_move_speed must be positive, got {}",
+                self.max_move_speed
+            ));
+        }
+        if !self.max_turn_speed.is_finite() || self.max_turn_speed <= 0.0 {
+            return invalid(format!(
+                "max_turn_speed must be positive, got {}",
+                self.max_turn_speed
+            ));
+        }
+        if let Some(lidar) = &self.observations.lidar {
+            if lidar.rays == 0 {
+                return invalid("lidar rays must be at least 1".to_string());
+            }
+            if !(lidar.fov > 0.0 && lidar.fov <= 360.0) {
+                return invalid(format!(
+                    "lidar fov must be in (0, 360] degrees, got {}",
+                    lidar.fov
+                ));
+            }
+            if !lidar.max_range.is_finite() || lidar.max_range <= 0.0 {
+                return invalid(format!(
+                    "lidar max_range must be positive, got {}",
+                    lidar.max_range
+                ));
+            }
+        }
+        if let Some(occupancy) = &self.observations.occupancy {
+            if occupancy.size == 0 {
+                return invalid("occupancy size must be at least 1".to_string());
+            }
+            if !occupancy.cell_size.is_finite() || occupancy.cell_size <= 0.0 {
+                return invalid(format!(
+                    "occupancy cell_size must be positive, got {}",
+                    occupancy.cell_size
+                ));
+            }
+            if !occupancy.shot_ttl.is_finite() || occupancy.shot_ttl < 0.0 {
+                return invalid(format!(
+                    "occupancy shot_ttl must not be negative, got {}",
+                    occupancy.shot_ttl
+                ));
+            }
+        }
+        if self.observations.segmentation && self.num_agents > MAX_SEGMENTATION_AGENTS {
+            return invalid(format!(
+                "segmentation supports at most {MAX_SEGMENTATION_AGENTS} agents, got {}",
+                self.num_agents
+            ));
+        }
+        if let Some(depth) = &self.observations.depth {
+            if !depth.max_depth.is_finite() || depth.max_depth <= 0.0 {
+                return invalid(format!(
+                    "depth max_depth must be positive, got {}",
+                    depth.max_depth
+                ));
+            }
+        }
+        let rewards = &self.rewards;
+        let weights = [
+            ("kill", rewards.kill),
+            ("damage_dealt", rewards.damage_dealt),
+            ("damage_taken", rewards.damage_taken),
+            ("death", rewards.death),
+            ("survival_per_tick", rewards.survival_per_tick),
+            ("shot_fired", rewards.shot_fired),
+            ("missed_shot", rewards.missed_shot),
+            ("wall_destroyed", rewards.wall_destroyed),
+            ("team_kill", rewards.team_kill),
+            ("team_death", rewards.team_death),
+            ("team_win", rewards.team_win),
+            ("team_loss", rewards.team_loss),
+            ("flag_capture", rewards.flag_capture),
+            ("flag_return", rewards.flag_return),
+            ("hill_point", rewards.hill_point),
+        ];
+        if let Some((name, weight)) = weights.iter().find(|(_, w)| !w.is_finite()) {
+            return invalid(format!("reward weight {name} must be finite, got {weight}"));
+        }
+        if let Some(generator) = &self.generator {
+            if self.map.is_some() {
+                return invalid("map and generator can't both be set".to_string());
+            }
+            generator.validate()?;
+        }
+        if let Some(pool) = &self.map_pool {
+            if self.map.is_some() || self.generator.is_some() {
+                return invalid("map_pool can't be combined with map or generator".to_string());
+            }
+            if pool.maps.is_empty() {
+                return invalid("map_pool needs at least one map".to_string());
+            }
+            for (index, entry) in pool.maps.iter().enumerate() {
+                match (&entry.path, &entry.generator) {
+                    (Some(_), None) => {}
+                    (None, Some(generator)) => generator.validate()?,
+                    _ => {
+                        return invalid(format!(
+                            "map_pool map {index} needs exactly one of path and generator"
+                        ))
+                    }
+                }
+                if !entry.weight.is_finite() || entry.weight < 0.0 {
+                    return invalid(format!(
+                        "map_pool map {index} weight must not be negative, got {}",
+                        entry.weight
+                    ));
+                }
+            }
+            if pool.strategy == PoolStrategy::Weighted
+                && pool.maps.iter().all(|entry| entry.weight == 0.0)
+            {
+                return invalid("map_pool needs a map with a positive weight".to_string());
+            }
+        }
+        if self.mode == RunMode::Eval && self.episodes == 0 {
+            return invalid("episodes must be at least 1 in eval mode".to_string());
+        }
+
+        Ok(())
+    }
+
+    /// Checks that a map fits this configuration
+    pub(crate) fn check_map(&self, game_map: &GameMap) -> Result<(), String> {
+        game_map.validate(self.num_agents)?;
+        game_mode(self).check_map(self, game_map)
+    }
+
+    /// Generates the map, or reads `map`, the first map of `map_pool` or the embedded map, and
+    /// checks it
+    pub(crate) fn load_map(&self) -> Result<GameMap, ConfigError> {
+        if let Some(generator) = &self.generator {
+            let seed = generator.map_seed(self.seed.unwrap_or_else(rand::random));
+            return self.generate_map(generator, seed);
+        }
+        if let Some(entry) = self.map_pool.as_ref().and_then(|pool| pool.maps.first()) {
+            return match (&entry.path, &entry.generator) {
+                (_, Some(generator)) => {
+                    let seed = generator.map_seed(self.seed.unwrap_or_else(rand::random));
+                    self.generate_map(generator, seed)
+                }
+                (path, None) => self.read_map(path.as_deref()),
+            };
+        }
+
+        self.read_map(self.map.as_deref())
+    }
+
+    /// Generates a map and checks it
+    pub(crate) fn generate_map(
+        &self,
+        generator: &GeneratorConfig,
+        seed: u64,
+    ) -> Result<GameMap, ConfigError> {
+        let game_map = mapgen::generate(generator, seed);
+        self.check_map(&game_map)
+            .map_err(|msg| ConfigError::InvalidMap(format!("generated map (seed {seed})"), msg))?;
+        Ok(game_map)
+    }
+
+    /// Reads a JSON or `.txt` map file, or the embedded map, and checks it
+    pub(crate) fn read_map(&self, path: Option<&Path>) -> Result<GameMap, ConfigError> {
+        let game_map: GameMap = match path {
+            Some(path) => {
+                let contents = fs::read_to_string(path)
+                    .map_err(|err| ConfigError::Io(path.to_path_buf(), err))?;
+                let parsed = match path.extension().and_then(|e| e.to_str()) {
+                    Some("txt") => GameMap::from_text(&contents),
+                    _ => serde_json::from_str(&contents).map_err(|err| err.to_string()),
+                };
+                parsed.map_err(|err| ConfigError::Parse(path.to_path_buf(), err))?
+            }
+            None => GameMap::default(),
+        };
+
+        self.check_map(&game_map).map_err(|msg| {
+            let source = match path {
+                Some(path) => format!("map {}", path.display()),
+                None => "embedded map".to_string(),
+            };
+            ConfigError::InvalidMap(source, msg)
+        })?;
+
+        Ok(game_map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn damage(falloff: bool, critical: bool) -> DamageConfig {
+        DamageConfig {
+            falloff: falloff.then(FalloffConfig::default),
+            critical: critical.then(CriticalConfig::default),
+        }
+    }
+
+    #[test]
+    fn plain_damage() {
+        let config = damage(false, false);
+        assert_eq!(config.hit_damage(40, 0.0, false), 40);
+        assert_eq!(config.hit_damage(40, 1000.0, true), 40);
+    }
+
+    #[test]
+    fn falloff_is_linear_between_start_and_end() {
+        let config = damage(true, false);
+        assert_eq!(config.hit_damage(100, 5.0, false), 100);
+        assert_eq!(config.hit_damage(100, 10.0, false), 100);
+        assert_eq!(config.hit_damage(100, 30.0, false), 63);
+        assert_eq!(config.hit_damage(100, 50.0, false), 25);
+        assert_eq!(config.hit_damage(100, 500.0, false), 25);
+    }
+
+    #[test]
+    fn critical_multiplies_after_falloff() {
+        let config = damage(true, true);
+        assert_eq!(config.hit_damage(100, 0.0, true), 200);
+        assert_eq!(config.hit_damage(100, 0.0, false), 100);
+        assert_eq!(config.hit_damage(100, 50.0, true), 50);
+    }
+
+    #[test]
+    fn hits_deal_at_least_one_damage() {
+        assert_eq!(damage(true, false).hit_damage(1, 100.0, false), 1);
+    }
+}
@@ -1,77 +1,171 @@
-#![allow(clippy::approx_constant)]
+use std::collections::HashSet;
+
 use bevy::prelude::*;
 use bevy_rapier3d::prelude::*;
 
-use crate::{actions::*, actors::*, events::*};
+use crate::{actions::*, actors::*, config::*, events::*, level::*, weapons::*};
+
+/// Scales an agent's movement speed, e.g. while it carries a flag
+#[derive(Component)]
+pub(crate) struct MoveSpeedScale(pub(crate) f32);
 
 #[derive(Resource)]
 pub(crate) struct DelayedControlTimer(pub(crate) Timer);
 
+/// Sum of the unit vectors of the movement flags, so opposite flags cancel out and diagonals
+/// are faster than straight moves
+pub(crate) fn move_direction(actions: &Actions, transform: &Transform) -> Vec3 {
+    let mut direction = Vec3::ZERO;
+    if actions.contains(Actions::FORWARD) {
+        direction += transform.forward();
+    }
+    if actions.contains(Actions::BACKWARD) {
+        direction += transform.back();
+    }
+    if actions.contains(Actions::LEFT) {
+        direction += transform.left();
+    }
+    if actions.contains(Actions::RIGHT) {
+        direction += transform.right();
+    }
+    direction
+}
+
 pub(crate) fn control_agents(
-    agent_actions: Vec<Option<Actions>>,
-    mut agent_movement_query: Query<(&mut Velocity, &mut Transform, &Actor)>,
+    agent_actions: Vec<Option<AgentAction>>,
+    config: &GameConfig,
+    mut agent_movement_query: Query<(
+        Entity,
+        &mut Velocity,
+        &mut Transform,
+        &Actor,
+        &AgentId,
+        &mut Arsenal,
+        Option<&MoveSpeedScale>,
+    )>,
+    obstacles: Query<(), Or<(With<Wall>, With<Actor>)>>,
     mut collision_events: EventReader<CollisionEvent>,
     mut event_gun_shot: EventWriter<EventGunShot>,
 ) {
-    for (i, (mut velocity, transform, actor)) in agent_movement_query.iter_mut().enumerate() {
+    // Agents that ran into a wall or another agent, rockets and sensors don't stop them
+    let mut collided = HashSet::new();
+    for event in collision_events.iter() {
+        let (CollisionEvent::Started(a, b, flags) | CollisionEvent::Stopped(a, b, flags)) = event;
+        if flags.contains(CollisionEventFlags::SENSOR) {
+            continue;
+        }
+        for (entity, other) in [(*a, *b), (*b, *a)] {
+            if obstacles.contains(other) {
+                collided.insert(entity);
+            }
+        }
+    }
+
+    for (entity, mut velocity, transform, actor, id, mut arsenal, speed_scale) in
+        agent_movement_query.iter_mut()
+    {
         *velocity = Velocity { ..default() };
 
         if actor.health == 0 {
             continue;
         }
 
-        if agent_actions[i].is_some() {
-            let agent_actions = agent_actions[i].clone().unwrap();
+        let speed = config.max_move_speed * speed_scale.map_or(1.0, |scale| scale.0);
+        match agent_actions.get(id.0).and_then(Option::as_ref) {
+            Some(AgentAction::Discrete(agent_actions)) => {
+                let turn_speed = config.max_turn_speed;
 
-            if agent_actions.contains(Actions::FORWARD) {
                 *velocity = Velocity {
-                    linvel: velocity.linvel + 10. * transform.forward().normalize(),
+                    linvel: speed * move_direction(agent_actions, &transform),
                     ..default()
+                };
+                if agent_actions.contains(Actions::TURN_LEFT) {
+                    *velocity = Velocity {
+                        linvel: velocity.linvel,
+                        angvel: Vec3::new(0.2, turn_speed, 0.8),
+                    };
                 }
-            }
-            if agent_actions.contains(Actions::BACKWARD) {
-                *velocity = Velocity {
-                    linvel: velocity.linvel + 10. * transform.left().normalize(),
-                    ..default()
+                if agent_actions.contains(Actions::TURN_RIGHT) {
+                    *velocity = Velocity {
+                        linvel: velocity.linvel,
+                        angvel: Vec3::new(0.2, -turn_speed, 0.8),
+                    };
                 }
-            }
-            if agent_actions.contains(Actions::LEFT) {
-                *velocity = Velocity {
-                    linvel: velocity.linvel + 10. * -transform.forward().normalize(),
-                    ..default()
+                if agent_actions.contains(Actions::NEXT_WEAPON) {
+                    arsenal.select_next();
                 }
-            }
-            if agent_actions.contains(Actions::RIGHT) {
-                *velocity = Velocity {
-                    linvel: velocity.linvel + 10. * transform.right().normalize(),
-                    ..default()
+                if agent_actions.contains(Actions::RELOAD) {
+                    arsenal.current_mut().start_reload();
+                }
+                if agent_actions.contains(Actions::SHOOT) && arsenal.current_mut().try_fire() {
+                    event_gun_shot.send(EventGunShot {
+                        from: *id,
+                        weapon: arsenal.selected,
+                    });
                 }
             }
-            if agent_actions.contains(Actions::TURN_LEFT) {
-                *velocity = Velocity {
-                    linvel: velocity.linvel,
-                    angvel: Vec3::new(0.2, 0.5 * 3.14, 0.8),
-                };
-            }
-            if agent_actions.contains(Actions::TURN_RIGHT) {
+            Some(AgentAction::Continuous(action)) => {
+                let direction =
+                    action.move_z * transform.forward() + action.move_x * transform.right();
                 *velocity = Velocity {
-                    linvel: velocity.linvel,
-                    angvel: Vec3::new(0.2, -0.5 * 3.14, 0.8),
+                    linvel: speed * direction,
+                    angvel: Vec3::new(0.0, config.max_turn_speed * action.turn, 0.0),
                 };
+
+                if action.next_weapon > 0.0 {
+                    arsenal.select_next();
+                }
+                if action.reload > 0.0 {
+                    arsenal.current_mut().start_reload();
+                }
+                if action.fire > 0.0 && arsenal.current_mut().try_fire() {
+                    event_gun_shot.send(EventGunShot {
+                        from: *id,
+                        weapon: arsenal.selected,
+                    });
+                }
             }
-            if agent_actions.contains(Actions::SHOOT) {
-                event_gun_shot.send(EventGunShot {
-                    from: actor.name.to_string(),
-                });
-            }
+            None => {}
         }
 
-        collision_events.iter().for_each(|_| {
+        if collided.contains(&entity) {
             // Stop the motion upon collision
             *velocity = Velocity {
                 linvel: Vec3::ZERO,
                 angvel: Vec3::ZERO,
             };
-        });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn direction(action: &str) -> Vec3 {
+        let actions = Actions::parse(action).unwrap();
+        // Facing -z, so forward is -z and left is -x
+        move_direction(&actions, &Transform::IDENTITY)
+    }
+
+    #[test]
+    fn single_flags_move_along_their_axis() {
+        assert_eq!(direction("FORWARD"), Vec3::NEG_Z);
+        assert_eq!(direction("BACKWARD"), Vec3::Z);
+        assert_eq!(direction("LEFT"), Vec3::NEG_X);
+        assert_eq!(direction("RIGHT"), Vec3::X);
+        assert_eq!(direction("IDLE"), Vec3::ZERO);
+    }
+
+    #[test]
+    fn combined_flags_add_up() {
+        assert_eq!(direction("FORWARD|LEFT"), Vec3::new(-1.0, 0.0, -1.0));
+        assert_eq!(
+            direction(r#"["BACKWARD", "RIGHT", "SHOOT"]"#),
+            Vec3::new(1.0, 0.0, 1.0)
+        );
+        assert_eq!(direction("FORWARD|BACKWARD"), Vec3::ZERO);
+        // FORWARD | RIGHT
+        assert_eq!(direction("18"), Vec3::new(1.0, 0.0, -1.0));
     }
 }
@@ -0,0 +1,340 @@
+use std::collections::HashSet;
+
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
+use serde::Serialize;
+
+use crate::{
+    actors::*, config::*, control::*, game_mode::*, level::*, render::*, rewards::*, teams::*,
+};
+
+const FLAG_HEIGHT: f32 = 2.5;
+/// How high above its carrier's center a carried flag floats
+const CARRIED_FLAG_OFFSET: f32 = 1.5;
+
+/// Team scores are captures, the first team to `captures_to_win` wins
+pub(crate) struct CaptureTheFlag;
+
+impl GameMode for CaptureTheFlag {
+    fn name(&self) -> &'static str {
+        "capture the flag"
+    }
+
+    fn validate(&self, config: &GameConfig) -> Result<(), String> {
+        let Some(ctf) = &config.ctf else {
+            return Ok(());
+        };
+
+        if config.teams.is_none() {
+            return Err("capture the flag needs teams".to_string());
+        }
+        if ctf.captures_to_win == 0 {
+            return Err("ctf captures_to_win must be at least 1".to_string());
+        }
+        if !(ctf.carrier_speed > 0.0 && ctf.carrier_speed <= 1.0) {
+            return Err(format!(
+                "ctf carrier_speed must be in (0, 1], got {}",
+                ctf.carrier_speed
+            ));
+        }
+        if !ctf.return_time.is_finite() || ctf.return_time < 0.0 {
+            return Err(format!(
+                "ctf return_time must not be negative, got {}",
+                ctf.return_time
+            ));
+        }
+        if !ctf.radius.is_finite() || ctf.radius <= 0.0 {
+            return Err(format!("ctf radius must be positive, got {}", ctf.radius));
+        }
+
+        Ok(())
+    }
+
+    fn check_map(&self, config: &GameConfig, game_map: &GameMap) -> Result<(), String> {
+        let Some(teams) = &config.teams else {
+            return Ok(());
+        };
+
+        if game_map.flag_bases.len() < teams.sizes.len() {
+            return Err(format!(
+                "capture the flag needs a flag base for each of the {} teams, the map has {}",
+                teams.sizes.len(),
+                game_map.flag_bases.len()
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn build(&self, app: &mut App) {
+        app.add_event::<EventFlag>()
+            .add_system(spawn_flags.in_set(ModeSet::Setup))
+            .add_system(update_flags.in_set(ModeSet::Rules))
+            .add_system(flag_rewards.in_set(ModeSet::Rewards))
+            .add_system(observe_flags.in_set(ModeSet::Observe));
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum FlagState {
+    AtBase,
+    Carried,
+    Dropped,
+}
+
+// ------
+// Events
+// ------
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum FlagAction {
+    PickedUp,
+    /// The carrier died
+    Dropped,
+    Returned,
+    Captured,
+}
+
+/// Something `agent` did with the flag of team `flag` in capture the flag
+#[derive(Debug)]
+pub(crate) struct EventFlag {
+    pub(crate) agent: AgentId,
+    pub(crate) flag: Team,
+    pub(crate) action: FlagAction,
+}
+
+// ----------
+// Components
+// ----------
+
+/// Flag of a team, agents touch it through its sensor collider
+#[derive(Component, Serialize, Clone, Debug)]
+pub(crate) struct Flag {
+    pub(crate) team: Team,
+    pub(crate) state: FlagState,
+    /// Agent holding the flag while it is carried
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) carrier: Option<AgentId>,
+    /// Current position on the map, (x, z)
+    pub(crate) position: (f32, f32),
+    pub(crate) base: (usize, usize),
+    /// Seconds until a dropped flag returns to its base
+    pub(crate) return_in: f32,
+}
+
+impl Flag {
+    fn base_translation(&self) -> Vec3 {
+        Vec3::new(self.base.0 as f32, FLAG_HEIGHT / 2.0, self.base.1 as f32)
+    }
+
+    fn reset(&mut self) {
+        self.state = FlagState::AtBase;
+        self.carrier = None;
+        self.return_in = 0.0;
+    }
+}
+
+// -------
+// Systems
+// -------
+
+pub(crate) fn spawn_flags(
+    mut commands: Commands,
+    config: Res<GameConfig>,
+    game_map: Res<GameMap>,
+    pass_materials: Option<Res<PassMaterials>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    let (Some(ctf), Some(teams)) = (&config.ctf, &config.teams) else {
+        return;
+    };
+
+    let mesh = meshes.add(Mesh::from(shape::Box::new(0.3, FLAG_HEIGHT, 0.3)));
+    for (team, base) in game_map
+        .flag_bases
+        .iter()
+        .take(teams.sizes.len())
+        .enumerate()
+    {
+        let flag = Flag {
+            team: Team(team),
+            state: FlagState::AtBase,
+            carrier: None,
+            position: (base.0 as f32, base.1 as f32),
+            base: *base,
+            return_in: 0.0,
+        };
+        let mut entity = commands.spawn((
+            PbrBundle {
+                mesh: mesh.clone(),
+                material: materials.add(TEAM_COLORS[team % TEAM_COLORS.len()].into()),
+                transform: Transform::from_translation(flag.base_translation()),
+                ..default()
+            },
+            Collider::cylinder(FLAG_HEIGHT / 2.0, ctf.radius),
+            Sensor,
+            flag,
+            ModeEntity,
+        ));
+        if let Some(pass_materials) = &pass_materials {
+            entity.with_children(|parent| {
+                pass_materials.spawn_copies(
+                    parent,
+                    &mesh,
+                    Transform::IDENTITY,
+                    SegmentationClass::Flag,
+                );
+            });
+        }
+    }
+}
+
+/// Pickups, drops, returns and captures. A team scores by bringing an enemy flag to its own
+/// flag while that one is at its base. Carriers move at `CtfConfig::carrier_speed`.
+pub(crate) fn update_flags(
+    mut commands: Commands,
+    time: Res<Time>,
+    config: Res<GameConfig>,
+    rapier_context: Res<RapierContext>,
+    mut team_scores: ResMut<TeamScores>,
+    mut status: ResMut<ModeStatus>,
+    mut flags: Query<(Entity, &mut Flag, &mut Transform)>,
+    actors: Query<(Entity, &Actor, &AgentId, &Transform), Without<Flag>>,
+    mut event_flag: EventWriter<EventFlag>,
+) {
+    let Some(ctf) = &config.ctf else {
+        return;
+    };
+
+    // Carriers that died drop their flag where they stand
+    let mut carriers: HashSet<AgentId> = HashSet::new();
+    for (_, mut flag, _) in flags.iter_mut() {
+        let Some(carrier) = flag.carrier else {
+            continue;
+        };
+        match actors.iter().find(|(_, _, id, _)| **id == carrier) {
+            Some((_, actor, ..)) if actor.health > 0 => {
+                carriers.insert(carrier);
+            }
+            carrier_actor => {
+                if let Some((entity, ..)) = carrier_actor {
+                    commands.entity(entity).remove::<MoveSpeedScale>();
+                }
+                flag.state = FlagState::Dropped;
+                flag.carrier = None;
+                flag.return_in = ctf.return_time;
+                event_flag.send(EventFlag {
+                    agent: carrier,
+                    flag: flag.team,
+                    action: FlagAction::Dropped,
+                });
+            }
+        }
+    }
+
+    let mut captures: Vec<AgentId> = Vec::new();
+    for (flag_entity, mut flag, _) in flags.iter_mut() {
+        if flag.state == FlagState::Dropped {
+            flag.return_in -= time.delta_seconds();
+            if flag.return_in <= 0.0 {
+                flag.reset();
+            }
+        }
+        if flag.state == FlagState::Carried {
+            continue;
+        }
+
+        for (actor_entity, actor, id, _) in actors.iter() {
+            if actor.health == 0
+                || rapier_context.intersection_pair(flag_entity, actor_entity) != Some(true)
+            {
+                continue;
+            }
+
+            if actor.team != Some(flag.team) {
+                if !carriers.insert(*id) {
+                    continue;
+                }
+                flag.state = FlagState::Carried;
+                flag.carrier = Some(*id);
+                commands
+                    .entity(actor_entity)
+                    .insert(MoveSpeedScale(ctf.carrier_speed));
+                event_flag.send(EventFlag {
+                    agent: *id,
+                    flag: flag.team,
+                    action: FlagAction::PickedUp,
+                });
+                break;
+            } else if flag.state == FlagState::Dropped {
+                flag.reset();
+                event_flag.send(EventFlag {
+                    agent: *id,
+                    flag: flag.team,
+                    action: FlagAction::Returned,
+                });
+            } else if carriers.remove(id) {
+                commands.entity(actor_entity).remove::<MoveSpeedScale>();
+                team_scores.0[flag.team.0] += 1;
+                captures.push(*id);
+            }
+        }
+    }
+
+    for (_, mut flag, mut transform) in flags.iter_mut() {
+        if let Some(carrier) = flag.carrier.filter(|carrier| captures.contains(carrier)) {
+            event_flag.send(EventFlag {
+                agent: carrier,
+                flag: flag.team,
+                action: FlagAction::Captured,
+            });
+            flag.reset();
+        }
+
+        transform.translation = match (flag.state, flag.carrier) {
+            (FlagState::Carried, Some(carrier)) => actors
+                .iter()
+                .find(|(_, _, id, _)| **id == carrier)
+                .map_or(transform.translation, |(.., carrier_transform)| {
+                    carrier_transform.translation + Vec3::Y * CARRIED_FLAG_OFFSET
+                }),
+            (FlagState::Dropped, _) => Vec3::new(
+                transform.translation.x,
+                FLAG_HEIGHT / 2.0,
+                transform.translation.z,
+            ),
+            _ => flag.base_translation(),
+        };
+        flag.position = (transform.translation.x, transform.translation.z);
+    }
+
+    status.leader = team_scores.leader().map(Team);
+    status.finished = team_scores
+        .0
+        .iter()
+        .any(|captures| *captures >= ctf.captures_to_win);
+}
+
+pub(crate) fn flag_rewards(
+    config: Res<GameConfig>,
+    mut step_rewards: ResMut<StepRewards>,
+    mut event_flag: EventReader<EventFlag>,
+) {
+    let weights = &config.rewards;
+    for flag in event_flag.iter() {
+        match flag.action {
+            FlagAction::Captured => step_rewards.add(flag.agent, weights.flag_capture),
+            FlagAction::Returned => step_rewards.add(flag.agent, weights.flag_return),
+            FlagAction::PickedUp | FlagAction::Dropped => {}
+        }
+    }
+}
+
+/// Flags in team order as `flags`
+pub(crate) fn observe_flags(mut observations: ResMut<ModeObservations>, flags: Query<&Flag>) {
+    let mut flags: Vec<&Flag> = flags.iter().collect();
+    flags.sort_by_key(|flag| flag.team.0);
+    observations.insert("flags", &flags);
+}
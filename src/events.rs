@@ -1,111 +1,241 @@
 use bevy::prelude::*;
 use bevy_mod_raycast::RaycastSource;
 use bevy_rapier3d::prelude::*;
-use bevy_rl::state::AIGymState;
 
-use crate::{actions::*, actors::Actor, game::*, gym::EnvironmentState, level::*};
+use crate::{actors::*, config::*, game::*, level::*, observations::*, teams::*};
 
 #[derive(Debug)]
 pub(crate) struct EventGunShot {
-    pub(crate) from: String,
+    pub(crate) from: AgentId,
+    /// Index of the weapon in `GameConfig::weapons`
+    pub(crate) weapon: usize,
 }
 
 #[derive(Debug)]
 pub(crate) struct EventDamage {
-    pub(crate) from: String,
-    pub(crate) to: String,
+    pub(crate) from: AgentId,
+    pub(crate) to: AgentId,
+    /// Weapon damage before `DamageConfig` modifiers
+    pub(crate) damage: u16,
+    /// Distance from the shooter's camera to the hit point, or from where a projectile was
+    /// launched to where it exploded
+    pub(crate) distance: f32,
+    /// The hit landed on the critical zone of the model, see `CriticalConfig`
+    pub(crate) critical: bool,
 }
 
+/// Health actually taken from `to` by a hit of `from`
 #[derive(Debug)]
-pub(crate) struct EventRoundOver;
+pub(crate) struct EventHit {
+    pub(crate) from: AgentId,
+    pub(crate) to: AgentId,
+    pub(crate) damage: u16,
+}
+
+/// Sent once when a hit brings an agent down to zero health
+#[derive(Debug)]
+pub(crate) struct EventDeath {
+    pub(crate) killer: AgentId,
+    pub(crate) victim: AgentId,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ShotOutcome {
+    Missed,
+    HitAgent,
+    DestroyedWall,
+}
+
+/// A shot that was actually fired, sent once its raycast is resolved
+#[derive(Debug)]
+pub(crate) struct EventShotFired {
+    pub(crate) from: AgentId,
+    pub(crate) outcome: ShotOutcome,
+}
+
+#[derive(Debug)]
+pub(crate) struct EventRoundOver {
+    /// Winning team in team modes, if any
+    pub(crate) winner: Option<Team>,
+    /// Seconds the round lasted
+    pub(crate) duration: f32,
+}
 
 // ------
 // Events
 // ------
 
 pub(crate) fn event_gun_shot(
-    mut commands: Commands,
-    shooting_query: Query<(&Parent, &RaycastSource<RaycastMarker>)>,
-    actor_query: Query<(Entity, &Children, &Actor)>,
-    wall_query: Query<(Entity, &Wall)>,
+    shooting_query: Query<
+        (&Parent, &GlobalTransform, &RaycastSource<RaycastMarker>),
+        Without<LidarRay>,
+    >,
+    actor_query: Query<(&Children, &AgentId, &Actor)>,
+    hit_transforms: Query<&GlobalTransform>,
+    mut walls: Query<&mut Wall>,
+    mut last_shot_query: Query<&mut LastShot>,
+    round_timer: Res<RoundTimer>,
+    mut recent_shots: Option<ResMut<RecentShots>>,
 
     mut gunshot_event: EventReader<EventGunShot>,
     mut event_damage: EventWriter<EventDamage>,
+    mut event_shot_fired: EventWriter<EventShotFired>,
+    config: Res<GameConfig>,
 ) {
     for gunshot_event in gunshot_event.iter() {
-        let result = shooting_query.iter().find(|(p, _)| {
+        let weapon = &config.weapons[gunshot_event.weapon];
+        if weapon.kind != WeaponKind::Hitscan {
+            continue;
+        }
+
+        let result = shooting_query.iter().find(|(p, ..)| {
             actor_query
-                .iter()
-                .any(|(e, _, _p)| e.index() == p.index() && _p.name == gunshot_event.from)
+                .get(p.get())
+                .map_or(false, |(_, id, _)| *id == gunshot_event.from)
         });
 
         if result.is_none() {
-            return;
+            continue;
+        }
+
+        let (shooter, transform, raycast_source) = result.unwrap();
+        let mut last_shot = last_shot_query.get_mut(shooter.get()).unwrap();
+        last_shot.hit = false;
+
+        // dead actors are hidden and don't stop shots, like with the other weapons
+        let r = raycast_source.intersections().iter().find(|(entity, _)| {
+            !actor_query
+                .iter()
+                .any(|(children, _, actor)| actor.health == 0 && children.contains(entity))
+        });
+
+        // remember the trace for occupancy observations
+        if let Some(recent_shots) = recent_shots.as_mut() {
+            let from = transform.translation();
+            let to = r.map_or(from + transform.forward() * MAX_SHOT_TRACE, |(_, hit)| {
+                hit.position()
+            });
+            recent_shots.push(RecentShot {
+                from,
+                to,
+                time: round_timer.0.elapsed_secs(),
+            });
         }
 
-        let (_, raycast_source) = result.unwrap();
-        let r = raycast_source.intersections().first();
         if r.is_none() {
+            event_shot_fired.send(EventShotFired {
+                from: gunshot_event.from,
+                outcome: ShotOutcome::Missed,
+            });
             continue;
         }
 
-        let hit_entity = r.unwrap().0;
+        let (hit_entity, hit) = r.unwrap();
+        let hit_entity = *hit_entity;
 
         let mut player_hit = false;
-        for (_, children, enemy) in actor_query.iter() {
+        for (children, enemy, _) in actor_query.iter() {
             let other_entity = children.iter().find(|c| c.index() == hit_entity.index());
             if other_entity.is_none() {
                 continue;
             }
 
+            // agent models are centered on their actor
+            let critical = hit_transforms.get(hit_entity).map_or(false, |model| {
+                is_critical_hit(
+                    config.damage.critical.as_ref(),
+                    hit.position(),
+                    model.translation(),
+                )
+            });
+
             event_damage.send(EventDamage {
-                from: gunshot_event.from.clone(),
-                to: enemy.name.clone(),
+                from: gunshot_event.from,
+                to: *enemy,
+                damage: weapon.damage,
+                distance: hit.distance(),
+                critical,
             });
 
             player_hit = true;
             continue;
         }
 
-        // despawn a wall
-        if !player_hit {
-            let wall_entity = wall_query
-                .iter()
-                .find(|(w, _)| w.index() == hit_entity.index());
-            if wall_entity.is_some() {
-                commands.entity(hit_entity).despawn_recursive();
+        last_shot.hit = player_hit;
+
+        let mut outcome = ShotOutcome::Missed;
+        if player_hit {
+            outcome = ShotOutcome::HitAgent;
+        } else {
+            // destroy the wall cell the shot went into
+            let inside = hit.position() + transform.forward() * WALL_HIT_DEPTH;
+            if destroy_wall_cell(&mut walls, hit_entity, inside, 0.0) {
+                outcome = ShotOutcome::DestroyedWall;
             }
         }
+
+        event_shot_fired.send(EventShotFired {
+            from: gunshot_event.from,
+            outcome,
+        });
     }
 }
 
 pub(crate) fn event_damage(
     mut commands: Commands,
-    mut player_query: Query<(Entity, &Children, &mut Actor, &mut Velocity)>,
+    mut player_query: Query<(Entity, &mut Actor, &AgentId)>,
     mut event_damage: EventReader<EventDamage>,
-    ai_gym_state: ResMut<AIGymState<Actions, EnvironmentState>>,
+    mut event_hit: EventWriter<EventHit>,
+    mut event_death: EventWriter<EventDeath>,
+    config: Res<GameConfig>,
 ) {
     for damage_event in event_damage.iter() {
         if damage_event.from == damage_event.to {
             continue;
         }
 
-        let mut ai_gym_state = ai_gym_state.lock().unwrap();
+        let team_of = |agent: AgentId| {
+            player_query
+                .iter()
+                .find(|(_, _, id)| **id == agent)
+                .and_then(|(_, actor, _)| actor.team)
+        };
+        let friendly_fire = config.teams.as_ref().map_or(true, |t| t.friendly_fire);
+        let teammates =
+            team_of(damage_event.from).map_or(false, |team| team_of(damage_event.to) == Some(team));
+        if teammates && !friendly_fire {
+            continue;
+        }
 
-        if let Some((i, (entity, _, mut actor, mut _velocity))) = player_query
+        if let Some((entity, mut actor, _)) = player_query
             .iter_mut()
-            .filter(|(_, _, actor, _)| actor.health > 0)
-            .enumerate()
-            .find(|(_, p)| p.2.name == damage_event.to)
+            .find(|(_, actor, id)| **id == damage_event.to && actor.health > 0)
         {
-            actor.health -= 100;
+            let damage = config.damage.hit_damage(
+                damage_event.damage,
+                damage_event.distance,
+                damage_event.critical,
+            );
+            let damage = actor.health.min(damage);
+            actor.health -= damage;
+            event_hit.send(EventHit {
+                from: damage_event.from,
+                to: damage_event.to,
+                damage,
+            });
+            if actor.health > 0 {
+                continue;
+            }
+
+            event_death.send(EventDeath {
+                killer: damage_event.from,
+                victim: damage_event.to,
+            });
 
             commands
                 .entity(entity)
                 .insert(Velocity { ..default() })
                 .insert(Visibility::Hidden);
-
-            ai_gym_state.set_reward(i, 10.0);
         }
     }
 }
@@ -1,9 +1,14 @@
-use bevy::prelude::*;
+use bevy::{prelude::*, window::ExitCondition};
 use bevy_mod_raycast::{DefaultPluginState, DefaultRaycastingPlugin};
 use bevy_rapier3d::prelude::*;
 use bevy_rl::*;
+use rand::{rngs::StdRng, SeedableRng};
 
-use crate::{actions::*, actors::*, events::*, gym::*, level::*};
+use crate::{
+    actions::*, actors::*, config::*, events::*, game_mode::*, gym::*, level::*, map_pool::*,
+    mapgen::*, modes::*, observations::*, render::*, rewards::*, seed_request::*, teams::*,
+    weapons::*,
+};
 
 // ----------
 // Components
@@ -15,10 +20,51 @@ pub(crate) struct RoundTimer(pub(crate) Timer);
 #[derive(Clone, Reflect)]
 pub(crate) struct RaycastMarker;
 
+/// Source of randomness for everything that happens inside an episode.
+///
+/// Reseeded at the start of every episode so that any episode can be replayed from its seed.
+#[derive(Resource)]
+pub(crate) struct GameRng {
+    pub(crate) rng: StdRng,
+    /// Seed the current episode was started with
+    pub(crate) seed: u64,
+    base_seed: Option<u64>,
+    episode: u64,
+}
+
+impl GameRng {
+    pub(crate) fn new(base_seed: Option<u64>) -> Self {
+        let seed = base_seed.unwrap_or_else(rand::random);
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+            seed,
+            base_seed,
+            episode: 0,
+        }
+    }
+
+    /// Episode `n` of a run with `--seed s` uses seed `s + n`, otherwise a fresh seed is drawn.
+    /// A `requested` seed, see `SeedRequest`, takes precedence.
+    pub(crate) fn begin_episode(&mut self, requested: Option<u64>) {
+        let seed = requested.unwrap_or_else(|| match self.base_seed {
+            Some(base_seed) => base_seed.wrapping_add(self.episode),
+            None => rand::random(),
+        });
+
+        self.episode += 1;
+        self.seed = seed;
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+}
+
 // -------
 // Systems
 // -------
 
+fn reseed_episode(mut rng: ResMut<GameRng>, seed_request: Option<Res<SeedRequest>>) {
+    rng.begin_episode(seed_request.and_then(|request| request.take()));
+}
+
 fn restart_round_timer(
     mut timer: ResMut<RoundTimer>,
     mut simulation_state: ResMut<NextState<SimulationState>>,
@@ -27,40 +73,68 @@ fn restart_round_timer(
     simulation_state.set(SimulationState::Running);
 }
 
-fn check_termination(
-    player_query: Query<&Actor>,
+pub(crate) fn check_termination(
+    player_query: Query<(&Actor, &AgentId)>,
+    config: Res<GameConfig>,
+    mut mode_status: ResMut<ModeStatus>,
     time: Res<Time>,
-    // mut app_state: ResMut<State<AppState>>,
     mut round_timer: ResMut<RoundTimer>,
     ai_gym_state: ResMut<AIGymState<Actions, EnvironmentState>>,
     mut event_round_over_writer: EventWriter<EventRoundOver>,
 ) {
-    let zero_health_actors = player_query.iter().filter(|p| p.health == 0).count() as u32;
-    round_timer.0.tick(time.delta());
-    let seconds_left = round_timer.0.duration().as_secs() - round_timer.0.elapsed().as_secs();
+    // The timer repeats, so its elapsed time wraps instead of reaching the duration
+    let time_up = round_timer.0.tick(time.delta()).just_finished();
 
     let mut ai_gym_state = ai_gym_state.lock().unwrap();
-    let ai_gym_settings = ai_gym_state.settings.clone();
-    let agents: Vec<&Actor> = player_query.iter().collect();
-    #[allow(clippy::needless_range_loop)]
-    for i in 0..agents.len() {
-        if agents[i].health == 0 {
-            ai_gym_state.set_terminated(i, true);
+    for (actor, id) in player_query.iter() {
+        if actor.health == 0 {
+            ai_gym_state.set_terminated(id.0, true);
         }
     }
 
-    if ai_gym_settings.num_agents == zero_health_actors || seconds_left == 0 {
-        event_round_over_writer.send(EventRoundOver);
+    // The game mode decides when the round is won and by whom, the timer ends it otherwise.
+    // The mode stays finished until the reset, but the round only ends once.
+    if !mode_status.round_over && (mode_status.finished || time_up) {
+        mode_status.round_over = true;
+        let winner = config.teams.as_ref().and(mode_status.leader);
+        let duration = if time_up {
+            round_timer.0.duration().as_secs_f32()
+        } else {
+            round_timer.0.elapsed_secs()
+        };
+        event_round_over_writer.send(EventRoundOver { winner, duration });
     }
 }
 
-pub(crate) fn build_game_app(_mode: String) -> App {
-    let gym_settings = AIGymSettings {
-        width: 256,
-        height: 256,
-        num_agents: 16,
-        pause_interval: 0.1,
-        render_to_buffer: true,
+pub(crate) fn build_game_app(
+    config: GameConfig,
+    game_map: GameMap,
+    map_pool: Option<MapPool>,
+) -> App {
+    let mode = config.mode;
+    let gym_settings = config.gym_settings();
+
+    let seed = match mode {
+        RunMode::Eval => config.seed.or(Some(EVAL_SEED)),
+        _ => config.seed,
+    };
+
+    // Train and eval only need the agent cameras, which render to bevy_rl images
+    let window_plugin = if mode.is_local() {
+        WindowPlugin {
+            primary_window: Some(Window {
+                title: "bevy_rl_shooter".to_string(),
+                resolution: (1280., 720.).into(),
+                ..default()
+            }),
+            ..default()
+        }
+    } else {
+        WindowPlugin {
+            primary_window: None,
+            exit_condition: ExitCondition::DontExit,
+            ..default()
+        }
     };
 
     let mut app = App::new();
@@ -69,50 +143,185 @@ pub(crate) fn build_game_app(_mode: String) -> App {
     app.insert_resource(ClearColor(Color::WHITE))
         .insert_resource(DefaultPluginState::<RaycastMarker>::default())
         .insert_resource(AIGymState::<Actions, EnvironmentState>::new(gym_settings))
-        .insert_resource(RoundTimer(Timer::from_seconds(60.0, TimerMode::Repeating)))
-        .init_resource::<GameMap>();
+        .insert_resource(RoundTimer(Timer::from_seconds(
+            config.round_length,
+            TimerMode::Repeating,
+        )))
+        .insert_resource(GameRng::new(seed))
+        .insert_resource(mode)
+        .init_resource::<TeamScores>()
+        .init_resource::<StepRewards>()
+        .insert_resource(config.clone())
+        .insert_resource(game_map);
+    if let Some(map_pool) = map_pool {
+        app.insert_resource(map_pool);
+    }
+    if !mode.is_local() {
+        let seed_request = SeedRequest::default();
+        match seed_request.listen(SEED_ADDRESS) {
+            Ok(_) => {
+                app.insert_resource(seed_request);
+            }
+            Err(err) => eprintln!("warning: cannot serve seed requests on {SEED_ADDRESS}: {err}"),
+        }
+    }
 
     // Events
     app.add_event::<EventGunShot>()
         .add_event::<EventDamage>()
+        .add_event::<EventHit>()
+        .add_event::<EventDeath>()
+        .add_event::<EventShotFired>()
         .add_event::<EventRoundOver>();
 
     // Plugins
-    app.add_plugins(DefaultPlugins)
+    app.add_plugins(DefaultPlugins.set(window_plugin))
         // .add_plugin(WorldInspectorPlugin::new())
         .add_plugin(RapierPhysicsPlugin::<NoUserData>::default())
         .add_plugin(DefaultRaycastingPlugin::<RaycastMarker>::default())
         // bevy_rl initialization
         .add_plugin(AIGymPlugin::<Actions, EnvironmentState>::default());
 
+    if config.observations.visual_passes().len() > 1 {
+        app.add_plugin(RenderPassesPlugin).add_system(
+            sync_agent_copies
+                .after(event_damage)
+                .in_set(OnUpdate(SimulationState::Running)),
+        );
+    }
+
+    app.add_plugin(GameModePlugin(game_mode(&config)));
+
     // Game world logic
     app.add_state::<SimulationState>();
 
+    app.add_startup_system(setup_projectile_assets);
+
     app.add_systems(
-        (spawn_game_world, spawn_computer_actors, restart_round_timer)
+        (
+            reseed_episode,
+            reset_team_scores,
+            reset_mode_status,
+            regenerate_map,
+            pick_pool_map,
+            spawn_game_world,
+            spawn_computer_actors,
+            restart_round_timer,
+        )
             .chain()
             .in_set(OnUpdate(SimulationState::Initializing)),
     );
 
     app.add_systems(
         (
+            update_weapons,
             event_gun_shot,
-            event_damage,
+            fire_shotgun,
+            launch_projectiles,
+            explode_projectiles,
+            event_damage
+                .after(event_gun_shot)
+                .after(fire_shotgun)
+                .after(explode_projectiles),
+            rebuild_walls
+                .after(event_gun_shot)
+                .after(fire_shotgun)
+                .after(explode_projectiles),
+            apply_rewards.after(event_damage).after(check_termination),
             event_round_over,
             check_termination,
         )
             .in_set(OnUpdate(SimulationState::Running)),
     );
 
-    app.add_systems(
-        (
-            bevy_rl_control_request,
-            bevy_rl_reset_request,
-            bevy_rl_pause_request,
-        )
-            .chain()
+    // Game mode systems, spawns are drawn before the mode's setup so that they don't depend
+    // on the mode
+    app.configure_set(
+        ModeSet::Setup
+            .after(spawn_computer_actors)
+            .before(restart_round_timer)
+            .in_set(OnUpdate(SimulationState::Initializing)),
+    )
+    .configure_set(
+        ModeSet::Rules
+            .after(event_damage)
+            .before(check_termination)
+            .in_set(OnUpdate(SimulationState::Running)),
+    )
+    .configure_set(
+        ModeSet::Rewards
+            .after(ModeSet::Rules)
+            .before(apply_rewards)
+            .in_set(OnUpdate(SimulationState::Running)),
+    )
+    .configure_set(
+        ModeSet::Observe
+            .before(bevy_rl_pause_request)
             .in_set(OnUpdate(SimulationState::PausedForControl)),
     );
 
+    if let Some(occupancy) = &config.observations.occupancy {
+        app.insert_resource(RecentShots {
+            ttl: occupancy.shot_ttl,
+            ..default()
+        });
+    }
+
+    if config.respawn.is_some() {
+        app.add_systems(
+            (schedule_respawns.after(event_damage), respawn_actors)
+                .in_set(OnUpdate(SimulationState::Running)),
+        );
+    }
+
+    if config.observations.lidar.is_some() {
+        app.add_system(update_lidar_scans.in_set(OnUpdate(SimulationState::Running)));
+    }
+
+    match mode {
+        RunMode::Train | RunMode::Eval => {
+            app.add_systems(
+                (
+                    bevy_rl_control_request,
+                    bevy_rl_reset_request,
+                    bevy_rl_pause_request,
+                )
+                    .chain()
+                    .in_set(OnUpdate(SimulationState::PausedForControl)),
+            );
+        }
+        RunMode::Play | RunMode::Spectate => {
+            // Pause has to be handled first, otherwise it would stop the physics
+            // pipeline right after local control resumed it
+            app.add_systems(
+                (bevy_rl_pause_request, local_control_request)
+                    .chain()
+                    .in_set(OnUpdate(SimulationState::PausedForControl)),
+            );
+            app.add_system(restart_local_round.in_set(OnUpdate(SimulationState::Running)));
+        }
+    }
+
+    // Mode-specific extras
+    match mode {
+        RunMode::Spectate => {
+            app.add_startup_system(spawn_spectator_camera)
+                .add_system(spectator_camera_movement);
+        }
+        RunMode::Eval => {
+            app.init_resource::<EvalSummary>();
+
+            app.add_system(eval_begin_episode.in_set(OnUpdate(SimulationState::Initializing)));
+            app.add_systems(
+                (
+                    eval_record_kills.after(event_damage),
+                    eval_record_episode.after(check_termination),
+                )
+                    .in_set(OnUpdate(SimulationState::Running)),
+            );
+        }
+        RunMode::Train | RunMode::Play => {}
+    }
+
     app
 }
@@ -0,0 +1,193 @@
+use std::collections::HashSet;
+
+use bevy::prelude::*;
+use serde_json::{Map, Value};
+
+use crate::{actors::*, config::*, ctf::*, events::*, koth::*, level::*, teams::*};
+
+/// Rules of a match on top of the shared combat, team and round systems.
+///
+/// A mode registers its resources and systems in `build`, placing systems in `ModeSet`s, and
+/// talks back to the core only through `ModeStatus`, `StepRewards` and `ModeObservations`.
+/// It checks its own settings and maps, and is made selectable by an entry in `MODES`.
+pub(crate) trait GameMode: Send + Sync + 'static {
+    /// Name used in config errors
+    fn name(&self) -> &'static str;
+
+    /// Checks the mode's settings, called by `GameConfig::validate`
+    fn validate(&self, _config: &GameConfig) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Checks that a map can host the mode, called by `GameConfig::check_map`
+    fn check_map(&self, _config: &GameConfig, _game_map: &GameMap) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn build(&self, app: &mut App);
+}
+
+/// Returns the mode when the config selects it
+type ModeSelector = fn(&GameConfig) -> Option<Box<dyn GameMode>>;
+
+/// Every mode besides deathmatch
+const MODES: [ModeSelector; 2] = [
+    |config| {
+        config
+            .ctf
+            .as_ref()
+            .map(|_| Box::new(CaptureTheFlag) as Box<dyn GameMode>)
+    },
+    |config| {
+        config
+            .koth
+            .as_ref()
+            .map(|_| Box::new(KingOfTheHill) as Box<dyn GameMode>)
+    },
+];
+
+/// Modes the config selects, at most one is valid
+pub(crate) fn selected_modes(config: &GameConfig) -> Vec<Box<dyn GameMode>> {
+    MODES.iter().filter_map(|select| select(config)).collect()
+}
+
+/// Mode chosen by the config, deathmatch unless another mode is configured
+pub(crate) fn game_mode(config: &GameConfig) -> Box<dyn GameMode> {
+    selected_modes(config)
+        .into_iter()
+        .next()
+        .unwrap_or_else(|| Box::new(Deathmatch))
+}
+
+pub(crate) struct GameModePlugin(pub(crate) Box<dyn GameMode>);
+
+impl Plugin for GameModePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ModeStatus>()
+            .init_resource::<ModeObservations>();
+        self.0.build(app);
+    }
+}
+
+/// Where mode systems run, ordered against the core systems in `build_game_app`
+#[derive(SystemSet, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum ModeSet {
+    /// While initializing, once the world and the actors are spawned
+    Setup,
+    /// Every tick, after damage is applied and before the round end is checked
+    Rules,
+    /// Every tick, adds mode rewards to `StepRewards` before they are handed out
+    Rewards,
+    /// On pause, before the state response is assembled
+    Observe,
+}
+
+// ----------
+// Components
+// ----------
+
+/// Entity that belongs to a single round and is despawned on reset
+#[derive(Component)]
+pub(crate) struct ModeEntity;
+
+// ---------
+// Resources
+// ---------
+
+/// What the mode says about the current round, see `check_termination`
+#[derive(Resource, Default)]
+pub(crate) struct ModeStatus {
+    /// The mode's win condition was met
+    pub(crate) finished: bool,
+    /// Team ahead on the mode's score, it wins when the round ends without a last team standing
+    pub(crate) leader: Option<Team>,
+    /// `EventRoundOver` was sent, so it isn't sent again before the next round
+    pub(crate) round_over: bool,
+}
+
+/// Extra fields of the state response, keyed by field name
+#[derive(Resource, Default)]
+pub(crate) struct ModeObservations(pub(crate) Map<String, Value>);
+
+impl ModeObservations {
+    pub(crate) fn insert(&mut self, field: &str, value: &impl serde::Serialize) {
+        match serde_json::to_value(value) {
+            Ok(value) => {
+                self.0.insert(field.to_string(), value);
+            }
+            Err(err) => error!("failed to serialize {field}: {err}"),
+        }
+    }
+}
+
+pub(crate) fn reset_mode_status(mut status: ResMut<ModeStatus>) {
+    *status = ModeStatus::default();
+}
+
+// ----------
+// Deathmatch
+// ----------
+
+/// Last agent or team standing, or the team with the most enemy kills when the time is up
+pub(crate) struct Deathmatch;
+
+impl GameMode for Deathmatch {
+    fn name(&self) -> &'static str {
+        "deathmatch"
+    }
+
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            (record_team_scores, check_elimination)
+                .chain()
+                .in_set(ModeSet::Rules),
+        );
+    }
+}
+
+/// Counts kills of enemies as team scores
+pub(crate) fn record_team_scores(
+    mut scores: ResMut<TeamScores>,
+    mut status: ResMut<ModeStatus>,
+    mut event_death: EventReader<EventDeath>,
+    teams: Query<(&AgentId, &Team)>,
+) {
+    let team_of = |agent: AgentId| {
+        teams
+            .iter()
+            .find(|(id, _)| **id == agent)
+            .map(|(_, team)| *team)
+    };
+
+    for death in event_death.iter() {
+        let (Some(killer), Some(victim)) = (team_of(death.killer), team_of(death.victim)) else {
+            continue;
+        };
+        if killer != victim {
+            scores.0[killer.0] += 1;
+        }
+    }
+
+    status.leader = scores.leader().map(Team);
+}
+
+/// Without respawns the round is over once everybody is dead or a single team is left, which
+/// then wins over the team with the most kills
+pub(crate) fn check_elimination(
+    config: Res<GameConfig>,
+    mut status: ResMut<ModeStatus>,
+    actors: Query<&Actor>,
+) {
+    if config.respawn.is_some() {
+        return;
+    }
+
+    let living: Vec<&Actor> = actors.iter().filter(|actor| actor.health > 0).collect();
+    let teams_alive: HashSet<Team> = living.iter().filter_map(|actor| actor.team).collect();
+    if living.is_empty() {
+        status.finished = true;
+    } else if teams_alive.len() == 1 {
+        status.finished = true;
+        status.leader = teams_alive.into_iter().next();
+    }
+}
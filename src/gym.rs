@@ -4,12 +4,37 @@ use bevy_rl::*;
 
 use serde::Serialize;
 
-use crate::{actions::*, actors::*, control::*, events::*, level::*};
+use crate::{
+    actions::*, actors::*, config::*, control::*, events::*, game::*, game_mode::*, level::*,
+    map_pool::*, observations::*, rewards::*, teams::*, weapons::*,
+};
 
 #[derive(Default, Serialize, Clone)]
 pub(crate) struct EnvironmentState {
     pub(crate) map: GameMap,
+    /// Id of the map picked for this episode, with a map pool
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) map_id: Option<String>,
+    /// Why the map picked for this episode was rejected; the previous map, `map_id`, is kept
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) map_error: Option<String>,
     pub(crate) actors: Vec<Actor>,
+    /// Seed the current episode was started with
+    pub(crate) seed: u64,
+    /// Per-agent selected weapon, ammunition and weapon timers in agent id order
+    pub(crate) weapons: Vec<Arsenal>,
+    /// Per-agent feature vectors in agent id order, when enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) proprioception: Option<Vec<Proprioception>>,
+    /// Per-agent lidar scans in agent id order, when enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) lidar: Option<Vec<LidarScan>>,
+    /// Per-agent egocentric top-down grids in agent id order, when enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) occupancy: Option<Vec<OccupancyGrid>>,
+    /// Fields added by the game mode, see `ModeObservations`
+    #[serde(flatten)]
+    pub(crate) mode: serde_json::Map<String, serde_json::Value>,
 }
 
 /// Handle bevy_rl::EventPauseResume
@@ -18,7 +43,22 @@ pub(crate) fn bevy_rl_pause_request(
     ai_gym_state: Res<AIGymState<Actions, EnvironmentState>>,
     mut rapier_configuration: ResMut<RapierConfiguration>,
     game_map: Res<GameMap>,
-    query_actors: Query<(Entity, &Actor)>,
+    map_pool: Option<Res<MapPool>>,
+    rng: Res<GameRng>,
+    config: Res<GameConfig>,
+    round_timer: Res<RoundTimer>,
+    recent_shots: Option<Res<RecentShots>>,
+    walls: Query<&Wall>,
+    mode_observations: Res<ModeObservations>,
+    query_actors: Query<(
+        &AgentId,
+        &Actor,
+        &Velocity,
+        &Transform,
+        &LastShot,
+        &Arsenal,
+        Option<&LidarScan>,
+    )>,
 ) {
     if pause_event_reader.iter().count() == 0 {
         return;
@@ -27,10 +67,56 @@ pub(crate) fn bevy_rl_pause_request(
     let _ = pause_event_reader.iter().last();
     // Pause simulation (physics engine)
     rapier_configuration.physics_pipeline_active = false;
+    // Every per-agent list is ordered by agent id
+    let mut agents: Vec<_> = query_actors.iter().collect();
+    agents.sort_by_key(|(id, ..)| id.0);
     // Collect state into serializable struct
+    let proprioception = config.observations.proprioception.then(|| {
+        agents
+            .iter()
+            .map(|(_, actor, velocity, transform, last_shot, arsenal, _)| {
+                Proprioception::new(
+                    actor,
+                    velocity,
+                    transform,
+                    last_shot,
+                    arsenal,
+                    config.starting_health,
+                    round_timer.0.remaining_secs(),
+                )
+            })
+            .collect()
+    });
+    let lidar = config.observations.lidar.as_ref().map(|_| {
+        agents
+            .iter()
+            .map(|(.., scan)| scan.cloned().unwrap_or_default())
+            .collect()
+    });
+    let occupancy = config.observations.occupancy.as_ref().map(|occupancy| {
+        let actors: Vec<(&Transform, bool, Option<Team>)> = agents
+            .iter()
+            .map(|(_, actor, _, transform, ..)| (*transform, actor.health > 0, actor.team))
+            .collect();
+        occupancy_grids(
+            occupancy,
+            &actors,
+            &wall_cells(walls.iter()),
+            recent_shots.as_deref().unwrap_or(&RecentShots::default()),
+            round_timer.0.elapsed_secs(),
+        )
+    });
     let env_state = EnvironmentState {
         map: game_map.clone(),
-        actors: query_actors.iter().map(|(_, a)| a.clone()).collect(),
+        map_id: map_pool.as_ref().map(|pool| pool.current.clone()),
+        map_error: map_pool.and_then(|pool| pool.error.clone()),
+        actors: agents.iter().map(|(_, a, ..)| (*a).clone()).collect(),
+        weapons: agents.iter().map(|(.., w, _)| (*w).clone()).collect(),
+        seed: rng.seed,
+        proprioception,
+        lidar,
+        occupancy,
+        mode: mode_observations.0.clone(),
     };
     // Set bevy_rl gym state
     let mut ai_gym_state = ai_gym_state.lock().unwrap();
@@ -43,40 +129,64 @@ pub(crate) fn bevy_rl_control_request(
     mut control_event_reader: EventReader<EventControl>,
     mut simulation_state: ResMut<NextState<SimulationState>>,
     mut rapier_configuration: ResMut<RapierConfiguration>,
-    query_actors: Query<(&mut Velocity, &mut Transform, &Actor)>,
+    mut step_rewards: ResMut<StepRewards>,
+    mut next_weapon_held: Local<Vec<bool>>,
+    config: Res<GameConfig>,
+    query_actors: Query<(
+        Entity,
+        &mut Velocity,
+        &mut Transform,
+        &Actor,
+        &AgentId,
+        &mut Arsenal,
+        Option<&MoveSpeedScale>,
+    )>,
+    obstacles: Query<(), Or<(With<Wall>, With<Actor>)>>,
     collision_events: EventReader<CollisionEvent>,
     event_gun_shot: EventWriter<EventGunShot>,
 ) {
     if let Some(control) = control_event_reader.iter().next() {
+        step_rewards.clear();
         let mut ai_gym_state = ai_gym_state.lock().unwrap();
         let ai_gym_settings = ai_gym_state.settings.clone();
         let unparsed_actions = &control.0;
-        let mut actions: Vec<Option<Actions>> =
+        let mut actions: Vec<Option<AgentAction>> =
             (0..ai_gym_settings.num_agents).map(|_| None).collect();
 
         for i in 0..unparsed_actions.len() {
             if let Some(unparsed_action) = unparsed_actions[i].clone() {
                 ai_gym_state.set_reward(i, 0.0);
-                // Pass control inputs to your agents
 
-                let action = match unparsed_action.as_str() {
-                    "FORWARD" => Some(Actions::FORWARD),
-                    "BACKWARD" => Some(Actions::BACKWARD),
-                    "LEFT" => Some(Actions::LEFT),
-                    "RIGHT" => Some(Actions::RIGHT),
-                    "TURN_LEFT" => Some(Actions::TURN_LEFT),
-                    "TURN_RIGHT" => Some(Actions::TURN_RIGHT),
-                    "SHOOT" => Some(Actions::SHOOT),
-                    _ => None,
+                // Pass control inputs to your agents
+                actions[i] = match config.action_space {
+                    ActionSpace::Discrete => {
+                        Actions::parse(&unparsed_action).map(AgentAction::Discrete)
+                    }
+                    ActionSpace::Continuous => {
+                        ContinuousActions::parse(&unparsed_action).map(AgentAction::Continuous)
+                    }
                 };
-
-                actions[i] = action;
             } else {
                 actions[i] = None;
             }
         }
 
-        control_agents(actions, query_actors, collision_events, event_gun_shot);
+        next_weapon_held.resize(actions.len(), false);
+        for (action, held) in actions.iter_mut().zip(next_weapon_held.iter_mut()) {
+            match action {
+                Some(AgentAction::Continuous(action)) => action.press(held),
+                _ => *held = false,
+            }
+        }
+
+        control_agents(
+            actions,
+            &config,
+            query_actors,
+            obstacles,
+            collision_events,
+            event_gun_shot,
+        );
         // Resume simulation (physics engine)
         rapier_configuration.physics_pipeline_active = true;
 
@@ -92,6 +202,9 @@ pub(crate) fn bevy_rl_reset_request(
     mut commands: Commands,
     mut walls: Query<Entity, &Wall>,
     mut players: Query<(Entity, &Actor)>,
+    projectiles: Query<Entity, With<Projectile>>,
+    mode_entities: Query<Entity, With<ModeEntity>>,
+    recent_shots: Option<ResMut<RecentShots>>,
     mut simulation_state: ResMut<NextState<SimulationState>>,
     ai_gym_state: Res<AIGymState<Actions, EnvironmentState>>,
 ) {
@@ -107,6 +220,15 @@ pub(crate) fn bevy_rl_reset_request(
         commands.entity(e).despawn_recursive();
     }
 
+    for e in projectiles.iter().chain(mode_entities.iter()) {
+        commands.entity(e).despawn_recursive();
+    }
+
+    // Old shots would otherwise show up early in the next round
+    if let Some(mut recent_shots) = recent_shots {
+        recent_shots.shots.clear();
+    }
+
     simulation_state.set(SimulationState::Initializing);
 
     let ai_gym_state = ai_gym_state.lock().unwrap();
@@ -0,0 +1,276 @@
+use bevy::prelude::*;
+use rand::prelude::SliceRandom;
+use serde::Serialize;
+
+use crate::{
+    actors::*, config::*, game::*, game_mode::*, level::*, render::*, rewards::*, teams::*,
+};
+
+const HILL_MARKER_HEIGHT: f32 = 0.05;
+
+/// Agents or teams score by holding the hill alone, the first to `score_to_win` wins
+pub(crate) struct KingOfTheHill;
+
+impl GameMode for KingOfTheHill {
+    fn name(&self) -> &'static str {
+        "king of the hill"
+    }
+
+    fn validate(&self, config: &GameConfig) -> Result<(), String> {
+        let Some(koth) = &config.koth else {
+            return Ok(());
+        };
+
+        if !koth.radius.is_finite() || koth.radius <= 0.0 {
+            return Err(format!("koth radius must be positive, got {}", koth.radius));
+        }
+        if !koth.points_per_second.is_finite() || koth.points_per_second <= 0.0 {
+            return Err(format!(
+                "koth points_per_second must be positive, got {}",
+                koth.points_per_second
+            ));
+        }
+        if !koth.score_to_win.is_finite() || koth.score_to_win <= 0.0 {
+            return Err(format!(
+                "koth score_to_win must be positive, got {}",
+                koth.score_to_win
+            ));
+        }
+        if !koth.relocate_interval.is_finite() || koth.relocate_interval < 0.0 {
+            return Err(format!(
+                "koth relocate_interval must not be negative, got {}",
+                koth.relocate_interval
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Hill>()
+            .add_event::<EventHillScore>()
+            .add_system(place_hill.in_set(ModeSet::Setup))
+            .add_system(update_hill.in_set(ModeSet::Rules))
+            .add_system(hill_rewards.in_set(ModeSet::Rewards))
+            .add_system(observe_hill.in_set(ModeSet::Observe));
+    }
+}
+
+// ------
+// Events
+// ------
+
+/// Points `agent` scored for itself or its team by standing in the hill
+#[derive(Debug)]
+pub(crate) struct EventHillScore {
+    pub(crate) agent: AgentId,
+    pub(crate) points: f32,
+}
+
+// ----------
+// Components
+// ----------
+
+/// Disc drawn on the floor under the hill
+#[derive(Component)]
+pub(crate) struct HillMarker;
+
+// ---------
+// Resources
+// ---------
+
+/// The zone agents fight over in king of the hill
+#[derive(Resource, Serialize, Clone, Debug, Default)]
+pub(crate) struct Hill {
+    /// Cell the zone is centered on
+    pub(crate) center: (usize, usize),
+    pub(crate) radius: f32,
+    /// Seconds until the zone moves, 0 when it never moves
+    pub(crate) relocate_in: f32,
+    /// Score of every team, or of every agent by id in free-for-all
+    pub(crate) scores: Vec<f32>,
+    /// Team or agent scoring right now, nobody when the zone is empty or contested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) holder: Option<usize>,
+    /// More than one team, or agent in free-for-all, is inside the zone
+    pub(crate) contested: bool,
+}
+
+impl Hill {
+    fn contains(&self, translation: Vec3) -> bool {
+        let center = Vec2::new(self.center.0 as f32, self.center.1 as f32);
+        Vec2::new(translation.x, translation.z).distance(center) <= self.radius
+    }
+
+    fn marker_translation(&self) -> Vec3 {
+        Vec3::new(
+            self.center.0 as f32,
+            HILL_MARKER_HEIGHT,
+            self.center.1 as f32,
+        )
+    }
+
+    /// Team or agent with strictly more points than everybody else
+    pub(crate) fn leader(&self) -> Option<usize> {
+        let best = self.scores.iter().copied().reduce(f32::max)?;
+        let mut leaders = self.scores.iter().enumerate().filter(|(_, s)| **s == best);
+        match (leaders.next(), leaders.next()) {
+            (Some((holder, _)), None) if best > 0.0 => Some(holder),
+            _ => None,
+        }
+    }
+}
+
+/// Map-defined hill cells when the map has any, otherwise any empty cell; never the current one
+fn next_hill_center(
+    game_map: &GameMap,
+    current: Option<(usize, usize)>,
+    rng: &mut GameRng,
+) -> (usize, usize) {
+    let cells = if game_map.hill_zones.is_empty() {
+        &game_map.empty_space
+    } else {
+        &game_map.hill_zones
+    };
+    let candidates: Vec<(usize, usize)> = cells
+        .iter()
+        .copied()
+        .filter(|cell| Some(*cell) != current)
+        .collect();
+
+    candidates
+        .choose(&mut rng.rng)
+        .or_else(|| cells.first())
+        .copied()
+        .unwrap_or_default()
+}
+
+// -------
+// Systems
+// -------
+
+pub(crate) fn place_hill(
+    mut commands: Commands,
+    config: Res<GameConfig>,
+    game_map: Res<GameMap>,
+    mut rng: ResMut<GameRng>,
+    mut hill: ResMut<Hill>,
+    mut markers: Query<&mut Transform, With<HillMarker>>,
+    pass_materials: Option<Res<PassMaterials>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    let Some(koth) = &config.koth else {
+        return;
+    };
+
+    let holders = config
+        .teams
+        .as_ref()
+        .map_or(config.num_agents as usize, |teams| teams.sizes.len());
+    *hill = Hill {
+        center: next_hill_center(&game_map, None, &mut rng),
+        radius: koth.radius,
+        relocate_in: koth.relocate_interval,
+        scores: vec![0.0; holders],
+        holder: None,
+        contested: false,
+    };
+
+    // The marker outlives rounds, like the floor
+    if let Ok(mut transform) = markers.get_single_mut() {
+        transform.translation = hill.marker_translation();
+        return;
+    }
+
+    let mesh = meshes.add(Mesh::from(shape::Cylinder {
+        radius: koth.radius,
+        height: HILL_MARKER_HEIGHT,
+        ..default()
+    }));
+    let mut marker = commands.spawn((
+        PbrBundle {
+            mesh: mesh.clone(),
+            material: materials.add(Color::ORANGE.into()),
+            transform: Transform::from_translation(hill.marker_translation()),
+            ..default()
+        },
+        HillMarker,
+    ));
+    if let Some(pass_materials) = &pass_materials {
+        marker.with_children(|parent| {
+            pass_materials.spawn_copies(
+                parent,
+                &mesh,
+                Transform::IDENTITY,
+                SegmentationClass::Hill,
+            );
+        });
+    }
+}
+
+/// Living agents inside the zone score for themselves or their team unless the zone is contested
+pub(crate) fn update_hill(
+    time: Res<Time>,
+    config: Res<GameConfig>,
+    game_map: Res<GameMap>,
+    mut rng: ResMut<GameRng>,
+    mut hill: ResMut<Hill>,
+    mut status: ResMut<ModeStatus>,
+    mut markers: Query<&mut Transform, With<HillMarker>>,
+    actors: Query<(&Actor, &AgentId, &Transform), Without<HillMarker>>,
+    mut event_hill_score: EventWriter<EventHillScore>,
+) {
+    let Some(koth) = &config.koth else {
+        return;
+    };
+
+    let inside: Vec<(AgentId, usize)> = actors
+        .iter()
+        .filter(|(actor, _, transform)| actor.health > 0 && hill.contains(transform.translation))
+        .map(|(actor, id, _)| (*id, actor.team.map_or(id.0, |team| team.0)))
+        .collect();
+
+    let holder = inside.first().map(|(_, holder)| *holder);
+    let contested = inside.iter().any(|(_, other)| Some(*other) != holder);
+    hill.holder = if contested { None } else { holder };
+    hill.contested = contested;
+
+    if let Some(holder) = hill.holder {
+        let points = koth.points_per_second * time.delta_seconds();
+        hill.scores[holder] += points;
+        for (agent, _) in inside {
+            event_hill_score.send(EventHillScore { agent, points });
+        }
+    }
+
+    // Without teams the leader is an agent, which has no team to win
+    status.leader = config.teams.as_ref().and(hill.leader()).map(Team);
+    status.finished = hill.scores.iter().any(|score| *score >= koth.score_to_win);
+
+    if koth.relocate_interval > 0.0 {
+        hill.relocate_in -= time.delta_seconds();
+        if hill.relocate_in <= 0.0 {
+            hill.center = next_hill_center(&game_map, Some(hill.center), &mut rng);
+            hill.relocate_in = koth.relocate_interval;
+            for mut transform in markers.iter_mut() {
+                transform.translation = hill.marker_translation();
+            }
+        }
+    }
+}
+
+pub(crate) fn hill_rewards(
+    config: Res<GameConfig>,
+    mut step_rewards: ResMut<StepRewards>,
+    mut event_hill_score: EventReader<EventHillScore>,
+) {
+    for score in event_hill_score.iter() {
+        step_rewards.add(score.agent, config.rewards.hill_point * score.points);
+    }
+}
+
+/// The zone and its scores as `hill`
+pub(crate) fn observe_hill(mut observations: ResMut<ModeObservations>, hill: Res<Hill>) {
+    observations.insert("hill", &*hill);
+}
@@ -1,15 +1,283 @@
-use bevy::prelude::*;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use bevy::{
+    prelude::*,
+    render::mesh::{Indices, PrimitiveTopology},
+};
 use bevy_mod_raycast::RaycastMesh;
 use bevy_rapier3d::prelude::*;
 
 use serde::{Deserialize, Serialize};
 
-use crate::{game::*, map};
+use crate::{game::*, map, render::*};
+
+/// Map cells must lie on the floor, which ends at this coordinate
+pub(crate) const MAP_SIZE: usize = 256;
+
+/// Tiles of the text map format, one character per cell
+const TILE_WALL: char = '#';
+const TILE_FLOOR: char = '.';
+/// Floor inside a spawn zone
+const TILE_SPAWN: char = 'S';
+/// Floor with the flag base of the team with this digit, the format holds up to ten bases
+const TILE_FLAG_BASE_RADIX: u32 = 10;
+/// Floor the hill can be centered on
+const TILE_HILL: char = 'K';
+/// Not part of the map
+const TILE_NONE: char = ' ';
+/// World units between neighbouring characters of the text format
+const TEXT_CELL_SIZE: usize = 2;
+/// World units between neighbouring wall cells, a wall cell is a cube of this side
+const WALL_CELL_SIZE: usize = 2;
+/// Side of the square of the map whose wall cells are merged into one entity. Smaller chunks
+/// are cheaper to rebuild when a cell is destroyed, larger ones mean fewer entities.
+const WALL_CHUNK_SIZE: usize = 32;
+/// How far past the point where a ray hits a wall the hit cell is looked up, so that hits on
+/// the seam between two cells go to the cell the ray enters
+pub(crate) const WALL_HIT_DEPTH: f32 = 0.05;
+
+/// Inclusive rectangle of map cells
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SpawnZone {
+    pub min: (usize, usize),
+    pub max: (usize, usize),
+}
+
+impl SpawnZone {
+    pub fn contains(&self, (x, z): (usize, usize)) -> bool {
+        (self.min.0..=self.max.0).contains(&x) && (self.min.1..=self.max.1).contains(&z)
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone, Resource)]
 pub struct GameMap {
     pub empty_space: Vec<(usize, usize)>,
     pub walls: Vec<(usize, usize)>,
+    /// Agents only spawn in empty cells inside these zones; anywhere when there are none
+    #[serde(default)]
+    pub spawn_zones: Vec<SpawnZone>,
+    /// Flag base cell of every team in capture the flag, indexed by team
+    #[serde(default)]
+    pub flag_bases: Vec<(usize, usize)>,
+    /// Cells the king of the hill zone is centered on; any empty cell when there are none
+    #[serde(default)]
+    pub hill_zones: Vec<(usize, usize)>,
+}
+
+impl GameMap {
+    /// Parses the text format: row `r`, column `c` is the cell `(2c, 2r)`
+    pub fn from_text(text: &str) -> Result<GameMap, String> {
+        // Bases by team, as they may appear in any order
+        let mut flag_bases: BTreeMap<usize, (usize, usize)> = BTreeMap::new();
+        let mut game_map = GameMap {
+            empty_space: Vec::new(),
+            walls: Vec::new(),
+            spawn_zones: Vec::new(),
+            flag_bases: Vec::new(),
+            hill_zones: Vec::new(),
+        };
+
+        for (row, line) in text.lines().enumerate() {
+            let z = row * TEXT_CELL_SIZE;
+            // Consecutive spawn tiles of a row become a single zone
+            let mut spawn_run: Option<(usize, usize)> = None;
+            for (column, tile) in line.trim_end().chars().enumerate() {
+                let cell = (column * TEXT_CELL_SIZE, z);
+                match tile {
+                    TILE_WALL => game_map.walls.push(cell),
+                    TILE_FLOOR | TILE_SPAWN => game_map.empty_space.push(cell),
+                    _ if tile.is_digit(TILE_FLAG_BASE_RADIX) => {
+                        let team = tile.to_digit(TILE_FLAG_BASE_RADIX).unwrap() as usize;
+                        if flag_bases.insert(team, cell).is_some() {
+                            return Err(format!(
+                                "line {}, column {}: second flag base of team {team}",
+                                row + 1,
+                                column + 1
+                            ));
+                        }
+                        game_map.empty_space.push(cell);
+                    }
+                    TILE_HILL => {
+                        game_map.empty_space.push(cell);
+                        game_map.hill_zones.push(cell);
+                    }
+                    TILE_NONE => {}
+                    _ => {
+                        return Err(format!(
+                            "line {}, column {}: unknown tile {tile:?}",
+                            row + 1,
+                            column + 1
+                        ))
+                    }
+                }
+
+                if tile == TILE_SPAWN {
+                    spawn_run = Some((spawn_run.map_or(cell.0, |(first, _)| first), cell.0));
+                } else if let Some((first, last)) = spawn_run.take() {
+                    game_map.spawn_zones.push(SpawnZone {
+                        min: (first, z),
+                        max: (last, z),
+                    });
+                }
+            }
+            if let Some((first, last)) = spawn_run {
+                game_map.spawn_zones.push(SpawnZone {
+                    min: (first, z),
+                    max: (last, z),
+                });
+            }
+        }
+
+        let missing = flag_bases
+            .keys()
+            .enumerate()
+            .find(|(expected, team)| expected != *team);
+        if let Some((expected, _)) = missing {
+            return Err(format!(
+                "no flag base for team {expected}, teams are numbered from 0 without gaps"
+            ));
+        }
+        game_map.flag_bases = flag_bases.into_values().collect();
+
+        Ok(game_map)
+    }
+
+    /// Writes the map in the text format. Cells with odd coordinates share a character with
+    /// their even neighbour.
+    pub fn to_text(&self) -> Result<String, String> {
+        if self.flag_bases.len() > TILE_FLAG_BASE_RADIX as usize {
+            return Err(format!(
+                "the text format holds up to {TILE_FLAG_BASE_RADIX} flag bases, the map has {}",
+                self.flag_bases.len()
+            ));
+        }
+
+        let position = |(x, z): (usize, usize)| (x / TEXT_CELL_SIZE, z / TEXT_CELL_SIZE);
+
+        let mut tiles: HashMap<(usize, usize), char> = HashMap::new();
+        for cell in &self.empty_space {
+            let in_zone = self.spawn_zones.iter().any(|zone| zone.contains(*cell));
+            let tile = if in_zone { TILE_SPAWN } else { TILE_FLOOR };
+            tiles.insert(position(*cell), tile);
+        }
+        for cell in &self.hill_zones {
+            tiles.insert(position(*cell), TILE_HILL);
+        }
+        for (team, cell) in self.flag_bases.iter().enumerate() {
+            let tile = char::from_digit(team as u32, TILE_FLAG_BASE_RADIX).unwrap();
+            tiles.insert(position(*cell), tile);
+        }
+        for cell in &self.walls {
+            tiles.insert(position(*cell), TILE_WALL);
+        }
+
+        let columns = tiles
+            .keys()
+            .map(|(column, _)| column + 1)
+            .max()
+            .unwrap_or(0);
+        let rows = tiles.keys().map(|(_, row)| row + 1).max().unwrap_or(0);
+        let mut text = String::new();
+        for row in 0..rows {
+            let line: String = (0..columns)
+                .map(|column| tiles.get(&(column, row)).copied().unwrap_or(TILE_NONE))
+                .collect();
+            text.push_str(line.trim_end());
+            text.push('\n');
+        }
+        Ok(text)
+    }
+
+    /// Empty cells agents may spawn in
+    pub fn spawn_cells(&self) -> Vec<(usize, usize)> {
+        let in_zone: Vec<(usize, usize)> = self
+            .empty_space
+            .iter()
+            .filter(|cell| self.spawn_zones.iter().any(|zone| zone.contains(**cell)))
+            .copied()
+            .collect();
+
+        if in_zone.is_empty() {
+            self.empty_space.clone()
+        } else {
+            in_zone
+        }
+    }
+
+    /// Checks that every cell is on the floor, that walls and empty cells don't overlap, that
+    /// flag bases are distinct, that flag bases and hill zones are empty cells, and that
+    /// `num_agents` agents have room to spawn
+    pub(crate) fn validate(&self, num_agents: u32) -> Result<(), String> {
+        let cell_lists = [
+            ("empty_space", &self.empty_space),
+            ("walls", &self.walls),
+            ("flag_bases", &self.flag_bases),
+            ("hill_zones", &self.hill_zones),
+        ];
+        for (list, cells) in cell_lists {
+            let out_of_bounds = cells
+                .iter()
+                .enumerate()
+                .find(|(_, (x, z))| *x >= MAP_SIZE || *z >= MAP_SIZE);
+            if let Some((i, cell)) = out_of_bounds {
+                return Err(format!(
+                    "{list}[{i}] {cell:?} is out of bounds, coordinates must be below {MAP_SIZE}"
+                ));
+            }
+        }
+
+        let walls: HashSet<(usize, usize)> = self.walls.iter().copied().collect();
+        let overlap = self
+            .empty_space
+            .iter()
+            .enumerate()
+            .find(|(_, cell)| walls.contains(*cell));
+        if let Some((i, cell)) = overlap {
+            return Err(format!("empty_space[{i}] {cell:?} is also a wall"));
+        }
+
+        for (i, cell) in self.flag_bases.iter().enumerate() {
+            if let Some(j) = self.flag_bases[..i].iter().position(|other| other == cell) {
+                return Err(format!("flag_bases[{i}] {cell:?} is also flag_bases[{j}]"));
+            }
+        }
+
+        let empty: HashSet<(usize, usize)> = self.empty_space.iter().copied().collect();
+        for (list, cells) in [
+            ("flag_bases", &self.flag_bases),
+            ("hill_zones", &self.hill_zones),
+        ] {
+            if let Some((i, cell)) = cells.iter().enumerate().find(|(_, c)| !empty.contains(*c)) {
+                return Err(format!("{list}[{i}] {cell:?} is not in empty_space"));
+            }
+        }
+
+        for (i, zone) in self.spawn_zones.iter().enumerate() {
+            if zone.min.0 > zone.max.0 || zone.min.1 > zone.max.1 {
+                return Err(format!(
+                    "spawn_zones[{i}] min {:?} is past max {:?}",
+                    zone.min, zone.max
+                ));
+            }
+        }
+
+        let spawn_cells = self.spawn_cells().len();
+        if spawn_cells < num_agents as usize {
+            return Err(format!(
+                "{spawn_cells} cells to spawn in are not enough for {num_agents} agents"
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Wall cells as they are at the start of a round, in `wall_cells` format
+    pub(crate) fn wall_set(&self) -> HashSet<(i32, i32)> {
+        self.walls
+            .iter()
+            .map(|(x, z)| (*x as i32, *z as i32))
+            .collect()
+    }
 }
 
 impl Default for GameMap {
@@ -19,8 +287,52 @@ impl Default for GameMap {
     }
 }
 
+// ----------
+// Components
+// ----------
+
+/// Wall cells of one `WALL_CHUNK_SIZE` square of the map, merged into a single mesh and a
+/// compound collider. Destroyed cells are removed from `cells` and `rebuild_walls` rebuilds
+/// the geometry.
 #[derive(Component)]
-pub(crate) struct Wall;
+pub(crate) struct Wall {
+    pub(crate) cells: BTreeSet<(usize, usize)>,
+}
+
+impl Wall {
+    /// Cell whose cube is closest to `point`, if it is at most `reach` away
+    fn cell_at(&self, point: Vec3, reach: f32) -> Option<(usize, usize)> {
+        let half = WALL_CELL_SIZE as f32 / 2.0;
+        self.cells
+            .iter()
+            .map(|(x, z)| {
+                let min = Vec3::new(*x as f32 - half, 0.0, *z as f32 - half);
+                let max = Vec3::new(*x as f32 + half, WALL_CELL_SIZE as f32, *z as f32 + half);
+                ((*x, *z), point.clamp(min, max).distance(point))
+            })
+            .filter(|(_, distance)| *distance <= reach)
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(cell, _)| cell)
+    }
+}
+
+/// Destroys the cell of wall `entity` closest to `point`, if it is at most `reach` away.
+/// Returns whether a cell was destroyed.
+pub(crate) fn destroy_wall_cell(
+    walls: &mut Query<&mut Wall>,
+    entity: Entity,
+    point: Vec3,
+    reach: f32,
+) -> bool {
+    let Ok(mut wall) = walls.get_mut(entity) else {
+        return false;
+    };
+    // Only touch the wall mutably when a cell goes, so that `rebuild_walls` skips the rest
+    match wall.cell_at(point, reach) {
+        Some(cell) => wall.cells.remove(&cell),
+        None => false,
+    }
+}
 
 #[derive(Bundle)]
 struct WallBundle {
@@ -32,10 +344,119 @@ struct WallBundle {
     raycast_marker: RaycastMesh<RaycastMarker>,
 }
 
+/// Greedy meshing: cells are merged into as few inclusive rectangles of cells as possible,
+/// first along z, then along x
+fn merge_cells(cells: &BTreeSet<(usize, usize)>) -> Vec<((usize, usize), (usize, usize))> {
+    let mut left = cells.clone();
+    let mut rects = Vec::new();
+    while let Some(&(x, z)) = left.iter().next() {
+        let mut max_z = z;
+        while left.contains(&(x, max_z + WALL_CELL_SIZE)) {
+            max_z += WALL_CELL_SIZE;
+        }
+        let mut max_x = x;
+        while (z..=max_z)
+            .step_by(WALL_CELL_SIZE)
+            .all(|cz| left.contains(&(max_x + WALL_CELL_SIZE, cz)))
+        {
+            max_x += WALL_CELL_SIZE;
+        }
+
+        for cx in (x..=max_x).step_by(WALL_CELL_SIZE) {
+            for cz in (z..=max_z).step_by(WALL_CELL_SIZE) {
+                left.remove(&(cx, cz));
+            }
+        }
+        rects.push(((x, z), (max_x, max_z)));
+    }
+    rects
+}
+
+/// Center and half extents of a rectangle of wall cells
+fn rect_box(((min_x, min_z), (max_x, max_z)): ((usize, usize), (usize, usize))) -> (Vec3, Vec3) {
+    let half = WALL_CELL_SIZE as f32 / 2.0;
+    let center = Vec3::new(
+        (min_x + max_x) as f32 / 2.0,
+        half,
+        (min_z + max_z) as f32 / 2.0,
+    );
+    let half_extents = Vec3::new(
+        (max_x - min_x) as f32 / 2.0 + half,
+        half,
+        (max_z - min_z) as f32 / 2.0 + half,
+    );
+    (center, half_extents)
+}
+
+/// One box per rectangle in world space, without the bottom faces that rest on the floor
+fn wall_mesh(rects: &[((usize, usize), (usize, usize))]) -> Mesh {
+    // Normal and two tangents of every face, the tangents' cross product is the normal
+    let faces = [
+        (Vec3::X, Vec3::Y, Vec3::Z),
+        (Vec3::NEG_X, Vec3::Z, Vec3::Y),
+        (Vec3::Y, Vec3::Z, Vec3::X),
+        (Vec3::Z, Vec3::X, Vec3::Y),
+        (Vec3::NEG_Z, Vec3::Y, Vec3::X),
+    ];
+
+    let mut positions: Vec<[f32; 3]> = Vec::new();
+    let mut normals: Vec<[f32; 3]> = Vec::new();
+    let mut uvs: Vec<[f32; 2]> = Vec::new();
+    let mut indices: Vec<u32> = Vec::new();
+    for rect in rects {
+        let (center, half_extents) = rect_box(*rect);
+        for (normal, u, v) in faces {
+            let face_center = center + normal * half_extents;
+            let (u, v) = (u * half_extents, v * half_extents);
+            let first = positions.len() as u32;
+            for (corner, uv) in [
+                (-u - v, [0.0, 1.0]),
+                (u - v, [1.0, 1.0]),
+                (u + v, [1.0, 0.0]),
+                (-u + v, [0.0, 0.0]),
+            ] {
+                positions.push((face_center + corner).to_array());
+                normals.push(normal.to_array());
+                uvs.push(uv);
+            }
+            indices.extend([first, first + 1, first + 2, first, first + 2, first + 3]);
+        }
+    }
+
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+    mesh.set_indices(Some(Indices::U32(indices)));
+    mesh
+}
+
+/// One cuboid per rectangle
+fn wall_collider(rects: &[((usize, usize), (usize, usize))]) -> Collider {
+    Collider::compound(
+        rects
+            .iter()
+            .map(|rect| {
+                let (center, half) = rect_box(*rect);
+                (
+                    center,
+                    Quat::IDENTITY,
+                    Collider::cuboid(half.x, half.y, half.z),
+                )
+            })
+            .collect(),
+    )
+}
+
+// -------
+// Systems
+// -------
+
 pub(crate) fn spawn_game_world(
     mut commands: Commands,
     game_map: Res<GameMap>,
     walls: Query<Entity, With<Wall>>,
+    pass_materials: Option<Res<PassMaterials>>,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
 ) {
@@ -49,34 +470,274 @@ pub(crate) fn spawn_game_world(
 
     // spawn floor only once
     if walls.iter().len() == 0 {
-        commands
-            .spawn(PbrBundle {
-                mesh,
-                material: white_material_handle.clone(),
-                ..Default::default()
-            })
+        let mut floor = commands.spawn(PbrBundle {
+            mesh: mesh.clone(),
+            material: white_material_handle.clone(),
+            ..Default::default()
+        });
+        floor
             .insert(RigidBody::Fixed)
             .insert(Collider::cuboid(256.0, 1.0, 256.0));
+        if let Some(pass_materials) = &pass_materials {
+            floor.with_children(|parent| {
+                pass_materials.spawn_copies(
+                    parent,
+                    &mesh,
+                    Transform::IDENTITY,
+                    SegmentationClass::Floor,
+                );
+            });
+        }
     }
 
-    let wall_mesh = meshes.add(Mesh::from(shape::Cube { size: 2.0 }));
-    let walls_iter: Vec<WallBundle> = game_map
-        .walls
-        .iter()
-        .map(|(x, z)| WallBundle {
+    // Thousands of cubes are merged into a few dozen entities, which keeps raycasts and
+    // physics cheap
+    let mut chunks: BTreeMap<(usize, usize), BTreeSet<(usize, usize)>> = BTreeMap::new();
+    for (x, z) in &game_map.walls {
+        chunks
+            .entry((x / WALL_CHUNK_SIZE, z / WALL_CHUNK_SIZE))
+            .or_default()
+            .insert((*x, *z));
+    }
+
+    for cells in chunks.into_values() {
+        let rects = merge_cells(&cells);
+        let wall_mesh = meshes.add(wall_mesh(&rects));
+        let mut wall = commands.spawn(WallBundle {
             pbr_pundle: PbrBundle {
                 mesh: wall_mesh.clone(),
                 material: white_material_handle.clone(),
-                transform: Transform::from_translation(Vec3::new(*x as f32, 1.0, *z as f32)),
-                global_transform: GlobalTransform::IDENTITY,
                 ..Default::default()
             },
             rigid_body: RigidBody::Fixed,
-            collider: Collider::cuboid(1.0, 1.0, 1.0),
+            collider: wall_collider(&rects),
             raycast_marker: RaycastMesh::<RaycastMarker>::default(),
-            wall: Wall,
-        })
-        .collect();
+            wall: Wall { cells },
+        });
+        // The copies share the mesh, so they follow `rebuild_walls` too
+        if let Some(pass_materials) = &pass_materials {
+            wall.with_children(|parent| {
+                pass_materials.spawn_copies(
+                    parent,
+                    &wall_mesh,
+                    Transform::IDENTITY,
+                    SegmentationClass::Wall,
+                );
+            });
+        }
+    }
+}
+
+/// Rebuilds the mesh and collider of walls that lost cells this tick, empty walls are despawned
+pub(crate) fn rebuild_walls(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    walls: Query<(Entity, Ref<Wall>, &Handle<Mesh>), Changed<Wall>>,
+) {
+    for (entity, wall, mesh) in walls.iter() {
+        // Freshly spawned walls are already built
+        if wall.is_added() {
+            continue;
+        }
+        if wall.cells.is_empty() {
+            commands.entity(entity).despawn_recursive();
+            continue;
+        }
+
+        let rects = merge_cells(&wall.cells);
+        if let Some(mesh) = meshes.get_mut(mesh) {
+            *mesh = wall_mesh(&rects);
+        }
+        commands.entity(entity).insert(wall_collider(&rects));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Walled room with a spawn zone of two cells, a flag base and a hill
+    fn room() -> GameMap {
+        GameMap::from_text("#####\n#SS0#\n#.K.#\n#####\n").unwrap()
+    }
+
+    fn validation_error(game_map: &GameMap, num_agents: u32) -> String {
+        game_map.validate(num_agents).unwrap_err()
+    }
+
+    #[test]
+    fn valid_map() {
+        assert_eq!(room().validate(2), Ok(()));
+    }
+
+    #[test]
+    fn cell_out_of_bounds() {
+        let mut game_map = room();
+        game_map.walls.push((MAP_SIZE, 0));
+        assert!(validation_error(&game_map, 2).starts_with("walls[14]"));
+    }
+
+    #[test]
+    fn wall_overlaps_empty_cell() {
+        let mut game_map = room();
+        game_map.walls.push(game_map.empty_space[0]);
+        assert_eq!(
+            validation_error(&game_map, 2),
+            "empty_space[0] (2, 2) is also a wall"
+        );
+    }
+
+    #[test]
+    fn flag_base_not_empty() {
+        let mut game_map = room();
+        game_map.flag_bases.push((0, 0));
+        assert_eq!(
+            validation_error(&game_map, 2),
+            "flag_bases[1] (0, 0) is not in empty_space"
+        );
+    }
 
-    commands.spawn_batch(walls_iter);
+    #[test]
+    fn duplicate_flag_base() {
+        let mut game_map = room();
+        game_map.flag_bases.push(game_map.flag_bases[0]);
+        assert_eq!(
+            validation_error(&game_map, 2),
+            "flag_bases[1] (6, 2) is also flag_bases[0]"
+        );
+    }
+
+    #[test]
+    fn hill_not_empty() {
+        let mut game_map = room();
+        game_map.hill_zones.push((0, 0));
+        assert_eq!(
+            validation_error(&game_map, 2),
+            "hill_zones[1] (0, 0) is not in empty_space"
+        );
+    }
+
+    #[test]
+    fn inverted_spawn_zone() {
+        let mut game_map = room();
+        game_map.spawn_zones.push(SpawnZone {
+            min: (4, 2),
+            max: (2, 2),
+        });
+        assert!(validation_error(&game_map, 2).starts_with("spawn_zones[1]"));
+    }
+
+    #[test]
+    fn too_few_spawn_cells() {
+        assert_eq!(
+            validation_error(&room(), 3),
+            "2 cells to spawn in are not enough for 3 agents"
+        );
+    }
+
+    fn sorted(cells: &[(usize, usize)]) -> Vec<(usize, usize)> {
+        let mut cells = cells.to_vec();
+        cells.sort();
+        cells
+    }
+
+    #[test]
+    fn duel_text_round_trip() {
+        let text = include_str!("../maps/duel.txt");
+        let game_map = GameMap::from_text(text).unwrap();
+        assert_eq!(game_map.flag_bases, vec![(4, 12), (42, 12)]);
+        assert_eq!(game_map.to_text().unwrap(), text);
+    }
+
+    #[test]
+    fn embedded_map_text_round_trip() {
+        let game_map = GameMap::default();
+        let parsed = GameMap::from_text(&game_map.to_text().unwrap()).unwrap();
+        assert_eq!(sorted(&parsed.empty_space), sorted(&game_map.empty_space));
+        assert_eq!(sorted(&parsed.walls), sorted(&game_map.walls));
+        assert_eq!(parsed.flag_bases, game_map.flag_bases);
+    }
+
+    #[test]
+    fn flag_bases_keep_team_order() {
+        let game_map = GameMap::from_text("#1.0#\n").unwrap();
+        assert_eq!(game_map.flag_bases, vec![(6, 0), (2, 0)]);
+        assert_eq!(game_map.to_text().unwrap(), "#1.0#\n");
+    }
+
+    fn wall(cells: &[(usize, usize)]) -> Wall {
+        Wall {
+            cells: cells.iter().copied().collect(),
+        }
+    }
+
+    #[test]
+    fn merged_rects_cover_every_cell_once() {
+        let cells: BTreeSet<(usize, usize)> = GameMap::default().walls.into_iter().collect();
+        let mut covered = BTreeSet::new();
+        for ((min_x, min_z), (max_x, max_z)) in merge_cells(&cells) {
+            for x in (min_x..=max_x).step_by(WALL_CELL_SIZE) {
+                for z in (min_z..=max_z).step_by(WALL_CELL_SIZE) {
+                    assert!(covered.insert((x, z)), "({x}, {z}) is covered twice");
+                }
+            }
+        }
+        assert_eq!(covered, cells);
+    }
+
+    #[test]
+    fn merged_block_splits_around_a_removed_cell() {
+        let mut block = wall(&[
+            (0, 0),
+            (0, 2),
+            (0, 4),
+            (2, 0),
+            (2, 2),
+            (2, 4),
+            (4, 0),
+            (4, 2),
+            (4, 4),
+        ]);
+        assert_eq!(merge_cells(&block.cells), vec![((0, 0), (4, 4))]);
+
+        block.cells.remove(&(2, 2));
+        assert_eq!(
+            merge_cells(&block.cells),
+            vec![
+                ((0, 0), (0, 4)),
+                ((2, 0), (4, 0)),
+                ((2, 4), (4, 4)),
+                ((4, 2), (4, 2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn hit_on_a_seam_goes_to_the_entered_cell() {
+        // The cubes of (0, 0) and (2, 2) touch along the edge at x = 1, z = 1
+        let diagonal = wall(&[(0, 0), (2, 2)]);
+        let seam = Vec3::new(1.0, 1.0, 1.0);
+        assert_eq!(
+            diagonal.cell_at(seam + Vec3::NEG_X * WALL_HIT_DEPTH, 0.0),
+            Some((0, 0))
+        );
+        assert_eq!(
+            diagonal.cell_at(seam + Vec3::Z * WALL_HIT_DEPTH, 0.0),
+            Some((2, 2))
+        );
+    }
+
+    #[test]
+    fn cell_out_of_reach() {
+        let single = wall(&[(0, 0)]);
+        let outside = Vec3::new(1.5, 1.0, 0.0);
+        assert_eq!(single.cell_at(outside, 0.0), None);
+        assert_eq!(single.cell_at(outside, 1.0), Some((0, 0)));
+    }
+
+    #[test]
+    fn flag_base_team_missing() {
+        assert!(GameMap::from_text("#0.2#\n").is_err());
+        assert!(GameMap::from_text("#0.0#\n").is_err());
+    }
 }
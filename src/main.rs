@@ -1,24 +1,308 @@
 mod actions;
 mod actors;
+mod config;
 mod control;
+mod ctf;
 mod events;
 mod game;
+mod game_mode;
 mod gym;
+mod koth;
 mod level;
 mod map;
+mod map_pool;
+mod mapgen;
+mod modes;
+mod observations;
+mod render;
+mod rewards;
+mod seed_request;
+mod teams;
+mod weapons;
+
+use std::{fs, path::PathBuf, process};
 
 use clap::Parser;
 
+use config::{
+    ActionSpace, ConfigError, CtfConfig, DepthConfig, GameConfig, GeneratorConfig, GeneratorKind,
+    KothConfig, LidarConfig, MapPoolConfig, OccupancyConfig, PoolMapConfig, PoolStrategy,
+    RespawnConfig, TeamConfig,
+};
+use map_pool::MapPool;
+use modes::RunMode;
+
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None, )]
 struct Args {
-    #[clap(short, long, default_value = "train")]
-    mode: String,
+    /// TOML or JSON file with any of the settings below; flags take precedence
+    #[clap(short, long)]
+    config: Option<PathBuf>,
+    #[clap(short, long, value_enum)]
+    mode: Option<RunMode>,
+    /// Base seed for spawns, names and bots; random when not set (0 in eval mode)
+    #[clap(short, long)]
+    seed: Option<u64>,
+    /// Number of agents in a match
+    #[clap(short, long)]
+    num_agents: Option<u32>,
+    /// Agent camera width in pixels
+    #[clap(long)]
+    width: Option<u32>,
+    /// Agent camera height in pixels
+    #[clap(long)]
+    height: Option<u32>,
+    /// Seconds between control requests
+    #[clap(long)]
+    pause_interval: Option<f32>,
+    /// Round length in seconds
+    #[clap(long)]
+    round_length: Option<f32>,
+    /// Respawn dead agents after this many seconds instead of waiting for the round to end
+    #[clap(long)]
+    respawn_delay: Option<f32>,
+    /// Split agents into teams of these sizes, e.g. `--teams 4,4`
+    #[clap(long, value_delimiter = ',')]
+    teams: Option<Vec<u32>>,
+    /// Let hits on teammates deal damage
+    #[clap(long)]
+    friendly_fire: bool,
+    /// Play capture the flag, needs `--teams` and a flag base per team in the map
+    #[clap(long)]
+    ctf: bool,
+    /// Play king of the hill, alone or with `--teams`
+    #[clap(long)]
+    koth: bool,
+    #[clap(long)]
+    starting_health: Option<u16>,
+    /// Health removed by a single hit of any weapon
+    #[clap(long)]
+    weapon_damage: Option<u16>,
+    #[clap(long, value_enum)]
+    action_space: Option<ActionSpace>,
+    /// Movement speed at full throttle, in units per second
+    #[clap(long)]
+    max_move_speed: Option<f32>,
+    /// Turning speed at full throttle, in radians per second
+    #[clap(long)]
+    max_turn_speed: Option<f32>,
+    /// Return per-agent proprioceptive feature vectors in the state response
+    #[clap(long)]
+    proprioception: bool,
+    /// Cast this many lidar rays per agent and return them in the state response
+    #[clap(long)]
+    lidar_rays: Option<u32>,
+    /// Lidar field of view in degrees
+    #[clap(long)]
+    lidar_fov: Option<f32>,
+    /// Lidar range, hits further away are reported as nothing
+    #[clap(long)]
+    lidar_range: Option<f32>,
+    /// Return an egocentric occupancy grid with this many cells per side for every agent
+    #[clap(long)]
+    occupancy_size: Option<u32>,
+    /// Occupancy grid cell side in world units
+    #[clap(long)]
+    occupancy_cell_size: Option<f32>,
+    /// Render a depth pass with this range next to every agent's color image
+    #[clap(long)]
+    depth_range: Option<f32>,
+    /// Render a semantic segmentation pass next to every agent's color image
+    #[clap(long)]
+    segmentation: bool,
+    /// JSON map file, or text map file ending in `.txt`, to use instead of the embedded map
+    #[clap(long)]
+    map: Option<PathBuf>,
+    /// Generate a map of this kind instead of loading one
+    #[clap(long, value_enum)]
+    generator: Option<GeneratorKind>,
+    /// Generate a new map for every episode
+    #[clap(long)]
+    regenerate_map: bool,
+    /// Comma-separated map files to pick a new map from for every episode
+    #[clap(long, value_delimiter = ',')]
+    map_pool: Vec<PathBuf>,
+    /// How maps are picked from the map pool
+    #[clap(long, value_enum)]
+    map_pool_strategy: Option<PoolStrategy>,
+    /// Write the map in the text format to this file and exit
+    #[clap(long)]
+    export_map: Option<PathBuf>,
+    /// Number of episodes to run in eval mode
+    #[clap(long)]
+    episodes: Option<u32>,
+    /// Where eval mode writes its results summary
+    #[clap(long)]
+    output: Option<PathBuf>,
+}
+
+impl Args {
+    fn into_config(self) -> Result<GameConfig, ConfigError> {
+        let mut config = match &self.config {
+            Some(path) => GameConfig::from_file(path)?,
+            None => GameConfig::default(),
+        };
+
+        if let Some(mode) = self.mode {
+            config.mode = mode;
+        }
+        if self.seed.is_some() {
+            config.seed = self.seed;
+        }
+        if let Some(num_agents) = self.num_agents {
+            config.num_agents = num_agents;
+        }
+        if let Some(width) = self.width {
+            config.width = width;
+        }
+        if let Some(height) = self.height {
+            config.height = height;
+        }
+        if self.pause_interval.is_some() {
+            config.pause_interval = self.pause_interval;
+        }
+        if let Some(round_length) = self.round_length {
+            config.round_length = round_length;
+        }
+        if let Some(delay) = self.respawn_delay {
+            config.respawn = Some(RespawnConfig { delay });
+        }
+        if self.teams.is_some() || self.friendly_fire {
+            let teams = config.teams.get_or_insert_with(TeamConfig::default);
+            if let Some(sizes) = self.teams {
+                teams.sizes = sizes;
+            }
+            if self.friendly_fire {
+                teams.friendly_fire = true;
+            }
+        }
+        if self.ctf {
+            config.ctf.get_or_insert_with(CtfConfig::default);
+        }
+        if self.koth {
+            config.koth.get_or_insert_with(KothConfig::default);
+        }
+        if let Some(starting_health) = self.starting_health {
+            config.starting_health = starting_health;
+        }
+        if let Some(weapon_damage) = self.weapon_damage {
+            for weapon in &mut config.weapons {
+                weapon.damage = weapon_damage;
+            }
+        }
+        if let Some(action_space) = self.action_space {
+            config.action_space = action_space;
+        }
+        if let Some(max_move_speed) = self.max_move_speed {
+            config.max_move_speed = max_move_speed;
+        }
+        if let Some(max_turn_speed) = self.max_turn_speed {
+            config.max_turn_speed = max_turn_speed;
+        }
+        if self.proprioception {
+            config.observations.proprioception = true;
+        }
+        if self.lidar_rays.is_some() || self.lidar_fov.is_some() || self.lidar_range.is_some() {
+            let lidar = config
+                .observations
+                .lidar
+                .get_or_insert_with(LidarConfig::default);
+            if let Some(rays) = self.lidar_rays {
+                lidar.rays = rays;
+            }
+            if let Some(fov) = self.lidar_fov {
+                lidar.fov = fov;
+            }
+            if let Some(max_range) = self.lidar_range {
+                lidar.max_range = max_range;
+            }
+        }
+        if self.occupancy_size.is_some() || self.occupancy_cell_size.is_some() {
+            let occupancy = config
+                .observations
+                .occupancy
+                .get_or_insert_with(OccupancyConfig::default);
+            if let Some(size) = self.occupancy_size {
+                occupancy.size = size;
+            }
+            if let Some(cell_size) = self.occupancy_cell_size {
+                occupancy.cell_size = cell_size;
+            }
+        }
+        if let Some(max_depth) = self.depth_range {
+            config.observations.depth = Some(DepthConfig { max_depth });
+        }
+        if self.segmentation {
+            config.observations.segmentation = true;
+        }
+        if self.map.is_some() {
+            config.map = self.map;
+        }
+        if self.generator.is_some() || self.regenerate_map {
+            let generator = config
+                .generator
+                .get_or_insert_with(GeneratorConfig::default);
+            if let Some(kind) = self.generator {
+                generator.kind = kind;
+            }
+            if self.regenerate_map {
+                generator.regenerate = true;
+            }
+        }
+        if !self.map_pool.is_empty() || self.map_pool_strategy.is_some() {
+            let pool = config.map_pool.get_or_insert_with(MapPoolConfig::default);
+            if !self.map_pool.is_empty() {
+                pool.maps = self
+                    .map_pool
+                    .into_iter()
+                    .map(|path| PoolMapConfig {
+                        path: Some(path),
+                        ..Default::default()
+                    })
+                    .collect();
+            }
+            if let Some(strategy) = self.map_pool_strategy {
+                pool.strategy = strategy;
+            }
+        }
+        if let Some(episodes) = self.episodes {
+            config.episodes = episodes;
+        }
+        if let Some(output) = self.output {
+            config.output = output;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+fn exit_with_error(err: ConfigError) -> ! {
+    eprintln!("error: {err}");
+    process::exit(2);
 }
 
 fn main() {
     let args = Args::parse();
+    let export_map = args.export_map.clone();
+    let config = args
+        .into_config()
+        .unwrap_or_else(|err| exit_with_error(err));
+    let game_map = config.load_map().unwrap_or_else(|err| exit_with_error(err));
+    let map_pool = MapPool::load(&config).unwrap_or_else(|err| exit_with_error(err));
+
+    if let Some(path) = export_map {
+        let text = game_map.to_text().unwrap_or_else(|err| {
+            eprintln!("error: cannot export the map: {err}");
+            process::exit(1);
+        });
+        if let Err(err) = fs::write(&path, text) {
+            eprintln!("error: cannot write {}: {err}", path.display());
+            process::exit(1);
+        }
+        return;
+    }
 
-    let mut bevy_app = game::build_game_app(args.mode);
+    let mut bevy_app = game::build_game_app(config, game_map, map_pool);
     bevy_app.run();
 }
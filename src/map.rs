@@ -16306,5 +16306,15 @@ pub(crate) static JSON: &str = r#"{
             126,
             126
         ]
+    ],
+    "flag_bases": [
+        [
+            12,
+            18
+        ],
+        [
+            116,
+            92
+        ]
     ]
 }"#;
@@ -0,0 +1,200 @@
+use bevy::prelude::*;
+use rand::{prelude::SliceRandom, rngs::StdRng, Rng, SeedableRng};
+
+use crate::{config::*, game::*, level::*, mapgen};
+
+/// Mixed into the episode seed for the pick, so it doesn't replay the first draws of `GameRng`
+const MAP_POOL_SEED_SALT: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// Where a pool map comes from
+pub(crate) enum PoolMap {
+    /// Read, or generated from a fixed seed, and checked once at startup
+    Loaded(GameMap),
+    /// Generated from the episode seed every time the map is picked
+    Generated(GeneratorConfig),
+}
+
+pub(crate) struct PoolEntry {
+    pub(crate) id: String,
+    pub(crate) weight: f32,
+    pub(crate) map: PoolMap,
+}
+
+// ---------
+// Resources
+// ---------
+
+/// Maps of `GameConfig::map_pool`, one is picked for every episode
+#[derive(Resource)]
+pub(crate) struct MapPool {
+    pub(crate) strategy: PoolStrategy,
+    pub(crate) entries: Vec<PoolEntry>,
+    /// Next entry with the round-robin strategy
+    next: usize,
+    /// Id of the map in play, reported as `map_id`
+    pub(crate) current: String,
+    /// Why the map picked for this episode was rejected, reported as `map_error`
+    pub(crate) error: Option<String>,
+}
+
+impl MapPool {
+    /// Reads every map file of the pool and generates the maps of generators with a fixed
+    /// seed, so a broken pool fails at startup rather than in the middle of training. Maps
+    /// generated from the episode seed can only be checked when they are picked.
+    pub(crate) fn load(config: &GameConfig) -> Result<Option<Self>, ConfigError> {
+        let Some(pool) = &config.map_pool else {
+            return Ok(None);
+        };
+
+        let mut entries = Vec::with_capacity(pool.maps.len());
+        for (index, entry) in pool.maps.iter().enumerate() {
+            let (default_id, map) = match (&entry.path, &entry.generator) {
+                (_, Some(generator)) => (
+                    format!("{:?}-{index}", generator.kind).to_lowercase(),
+                    match generator.seed {
+                        Some(seed) => PoolMap::Loaded(config.generate_map(generator, seed)?),
+                        None => PoolMap::Generated(generator.clone()),
+                    },
+                ),
+                (path, None) => (
+                    path.as_ref()
+                        .map_or(format!("map-{index}"), |p| p.display().to_string()),
+                    PoolMap::Loaded(config.read_map(path.as_deref())?),
+                ),
+            };
+            entries.push(PoolEntry {
+                id: entry.id.clone().unwrap_or(default_id),
+                weight: entry.weight,
+                map,
+            });
+        }
+
+        // Matches the map `GameConfig::load_map` starts with
+        let current = entries.first().map(|e| e.id.clone()).unwrap_or_default();
+        Ok(Some(Self {
+            strategy: pool.strategy,
+            entries,
+            next: 0,
+            current,
+            error: None,
+        }))
+    }
+
+    fn pick(&mut self, rng: &mut StdRng) -> usize {
+        match self.strategy {
+            PoolStrategy::Uniform => rng.gen_range(0..self.entries.len()),
+            PoolStrategy::RoundRobin => {
+                let index = self.next % self.entries.len();
+                self.next = index + 1;
+                index
+            }
+            PoolStrategy::Weighted => {
+                let indices: Vec<usize> = (0..self.entries.len()).collect();
+                indices
+                    .choose_weighted(rng, |index| self.entries[*index].weight)
+                    .copied()
+                    .unwrap_or_default()
+            }
+        }
+    }
+}
+
+// -------
+// Systems
+// -------
+
+/// Swaps in a map from the pool at the start of every episode. The pick is seeded by the
+/// salted episode seed, so it neither shifts nor repeats the draws of spawning and the modes.
+/// A generated map that fails `check_map` is reported and the previous map is kept.
+pub(crate) fn pick_pool_map(
+    config: Res<GameConfig>,
+    rng: Res<GameRng>,
+    pool: Option<ResMut<MapPool>>,
+    mut game_map: ResMut<GameMap>,
+) {
+    let Some(mut pool) = pool else {
+        return;
+    };
+
+    let mut pick_rng = StdRng::seed_from_u64(rng.seed ^ MAP_POOL_SEED_SALT);
+    let index = pool.pick(&mut pick_rng);
+    let entry = &pool.entries[index];
+    let picked = match &entry.map {
+        PoolMap::Loaded(loaded) => loaded.clone(),
+        PoolMap::Generated(generator) => {
+            let generated = mapgen::generate(generator, generator.map_seed(rng.seed));
+            if let Err(err) = config.check_map(&generated) {
+                let message = format!("map {} is invalid with seed {}: {err}", entry.id, rng.seed);
+                warn!("keeping map {}, {message}", pool.current);
+                pool.error = Some(message);
+                return;
+            }
+            generated
+        }
+    };
+
+    pool.current = pool.entries[index].id.clone();
+    pool.error = None;
+    *game_map = picked;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(strategy: PoolStrategy, weights: &[f32]) -> MapPool {
+        let game_map = GameMap::from_text("###\n#.#\n###\n").unwrap();
+        MapPool {
+            strategy,
+            entries: weights
+                .iter()
+                .enumerate()
+                .map(|(index, weight)| PoolEntry {
+                    id: format!("map-{index}"),
+                    weight: *weight,
+                    map: PoolMap::Loaded(game_map.clone()),
+                })
+                .collect(),
+            next: 0,
+            current: "map-0".to_string(),
+            error: None,
+        }
+    }
+
+    fn picks(pool: &mut MapPool, count: usize) -> Vec<usize> {
+        let mut rng = StdRng::seed_from_u64(0);
+        (0..count).map(|_| pool.pick(&mut rng)).collect()
+    }
+
+    #[test]
+    fn round_robin_cycles_in_order() {
+        let mut pool = pool(PoolStrategy::RoundRobin, &[1.0, 1.0, 1.0]);
+        assert_eq!(picks(&mut pool, 7), vec![0, 1, 2, 0, 1, 2, 0]);
+    }
+
+    #[test]
+    fn uniform_picks_every_map() {
+        let mut pool = pool(PoolStrategy::Uniform, &[1.0, 1.0, 1.0]);
+        let picks = picks(&mut pool, 300);
+        for index in 0..3 {
+            let count = picks.iter().filter(|pick| **pick == index).count();
+            assert!(
+                (70..130).contains(&count),
+                "map {index} picked {count} times"
+            );
+        }
+    }
+
+    #[test]
+    fn weighted_picks_follow_weights() {
+        let mut pool = pool(PoolStrategy::Weighted, &[1.0, 3.0]);
+        let heavy = picks(&mut pool, 4000)
+            .iter()
+            .filter(|pick| **pick == 1)
+            .count();
+        assert!(
+            (2800..3200).contains(&heavy),
+            "heavy map picked {heavy} times"
+        );
+    }
+}
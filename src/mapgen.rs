@@ -0,0 +1,346 @@
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+use rand::{prelude::SliceRandom, rngs::StdRng, Rng, SeedableRng};
+
+use crate::{config::*, game::*, level::*};
+
+/// World units between neighbouring generator cells, same as the text map format
+const CELL_SIZE: usize = 2;
+/// Rooms and cover blocks stop being placed after this many failed tries
+const MAX_ATTEMPTS: usize = 500;
+
+/// Grid of wall flags, border cells are always walls
+struct Grid {
+    width: usize,
+    height: usize,
+    walls: Vec<bool>,
+}
+
+impl Grid {
+    fn new(width: usize, height: usize, filled: bool) -> Self {
+        let mut grid = Grid {
+            width,
+            height,
+            walls: vec![filled; width * height],
+        };
+        for x in 0..width {
+            grid.set(x, 0, true);
+            grid.set(x, height - 1, true);
+        }
+        for z in 0..height {
+            grid.set(0, z, true);
+            grid.set(width - 1, z, true);
+        }
+        grid
+    }
+
+    fn is_wall(&self, x: usize, z: usize) -> bool {
+        self.walls[z * self.width + x]
+    }
+
+    fn set(&mut self, x: usize, z: usize, wall: bool) {
+        self.walls[z * self.width + x] = wall;
+    }
+
+    fn is_inner(&self, x: usize, z: usize) -> bool {
+        x > 0 && z > 0 && x < self.width - 1 && z < self.height - 1
+    }
+
+    fn inner_area(&self) -> usize {
+        (self.width - 2) * (self.height - 2)
+    }
+
+    fn neighbours(&self, x: usize, z: usize) -> impl Iterator<Item = (usize, usize)> + '_ {
+        [(1, 0), (-1, 0), (0, 1), (0, -1)]
+            .into_iter()
+            .map(move |(dx, dz)| (x as isize + dx, z as isize + dz))
+            .filter(|(x, z)| *x >= 0 && *z >= 0)
+            .map(|(x, z)| (x as usize, z as usize))
+            .filter(|(x, z)| *x < self.width && *z < self.height)
+    }
+
+    /// Walls off floor that can't be reached from the largest open region
+    fn keep_largest_region(&mut self) {
+        let mut region = vec![usize::MAX; self.walls.len()];
+        let mut sizes: Vec<usize> = Vec::new();
+        for start in 0..self.walls.len() {
+            if self.walls[start] || region[start] != usize::MAX {
+                continue;
+            }
+
+            let id = sizes.len();
+            let mut size = 0;
+            let mut queue = VecDeque::from([start]);
+            region[start] = id;
+            while let Some(index) = queue.pop_front() {
+                size += 1;
+                let (x, z) = (index % self.width, index / self.width);
+                for (nx, nz) in self.neighbours(x, z) {
+                    let next = nz * self.width + nx;
+                    if !self.walls[next] && region[next] == usize::MAX {
+                        region[next] = id;
+                        queue.push_back(next);
+                    }
+                }
+            }
+            sizes.push(size);
+        }
+
+        let Some(largest) = (0..sizes.len()).max_by_key(|id| sizes[*id]) else {
+            return;
+        };
+        for (index, id) in region.iter().enumerate() {
+            if *id != usize::MAX && *id != largest {
+                self.walls[index] = true;
+            }
+        }
+    }
+
+    /// Floor becomes empty space, only walls next to floor are kept since nobody sees the rest
+    fn into_game_map(self) -> GameMap {
+        let cell = |x: usize, z: usize| (x * CELL_SIZE, z * CELL_SIZE);
+        let mut empty_space = Vec::new();
+        let mut walls = Vec::new();
+        for z in 0..self.height {
+            for x in 0..self.width {
+                if !self.is_wall(x, z) {
+                    empty_space.push(cell(x, z));
+                    continue;
+                }
+
+                let x_range = x.saturating_sub(1)..=(x + 1).min(self.width - 1);
+                let z_range = z.saturating_sub(1)..=(z + 1).min(self.height - 1);
+                let near_floor = z_range
+                    .flat_map(|nz| x_range.clone().map(move |nx| (nx, nz)))
+                    .any(|(nx, nz)| !self.is_wall(nx, nz));
+                if near_floor {
+                    walls.push(cell(x, z));
+                }
+            }
+        }
+
+        // Bases in opposite corners first, for up to four teams. On small or irregular maps two
+        // corners can share their closest cell, which then only gets one base.
+        let (far_x, far_z) = cell(self.width, self.height);
+        let corners = [(0, 0), (far_x, far_z), (far_x, 0), (0, far_z)];
+        let mut flag_bases = Vec::new();
+        for (cx, cz) in corners {
+            let closest = empty_space
+                .iter()
+                .min_by_key(|(x, z)| x.abs_diff(cx).pow(2) + z.abs_diff(cz).pow(2))
+                .copied();
+            if let Some(base) = closest.filter(|base| !flag_bases.contains(base)) {
+                flag_bases.push(base);
+            }
+        }
+
+        GameMap {
+            empty_space,
+            walls,
+            spawn_zones: Vec::new(),
+            flag_bases,
+            hill_zones: Vec::new(),
+        }
+    }
+}
+
+/// Recursive backtracker on odd cells, then `1 - density` of the inner walls are knocked out
+fn maze(width: usize, height: usize, density: f32, rng: &mut StdRng) -> Grid {
+    let mut grid = Grid::new(width, height, true);
+    let mut stack = vec![(1, 1)];
+    grid.set(1, 1, false);
+    while let Some(&(x, z)) = stack.last() {
+        let mut next: Vec<(usize, usize)> = [(2, 0), (-2, 0), (0, 2), (0, -2)]
+            .into_iter()
+            .map(|(dx, dz)| (x as isize + dx, z as isize + dz))
+            .filter(|(nx, nz)| *nx > 0 && *nz > 0)
+            .map(|(nx, nz)| (nx as usize, nz as usize))
+            .filter(|(nx, nz)| grid.is_inner(*nx, *nz) && grid.is_wall(*nx, *nz))
+            .collect();
+        next.shuffle(rng);
+
+        match next.first() {
+            Some(&(nx, nz)) => {
+                grid.set((x + nx) / 2, (z + nz) / 2, false);
+                grid.set(nx, nz, false);
+                stack.push((nx, nz));
+            }
+            None => {
+                stack.pop();
+            }
+        }
+    }
+
+    // Walls between two passages in a straight line, removing them adds loops
+    for z in 1..height - 1 {
+        for x in 1..width - 1 {
+            let between = |a: (usize, usize), b: (usize, usize)| {
+                !grid.is_wall(a.0, a.1) && !grid.is_wall(b.0, b.1)
+            };
+            let separates = grid.is_wall(x, z)
+                && (between((x - 1, z), (x + 1, z)) || between((x, z - 1), (x, z + 1)));
+            if separates && rng.gen::<f32>() >= density {
+                grid.set(x, z, false);
+            }
+        }
+    }
+
+    grid
+}
+
+/// Rooms covering about `density` of the map, each joined to the previous one by a corridor
+fn dungeon(width: usize, height: usize, density: f32, rng: &mut StdRng) -> Grid {
+    let mut grid = Grid::new(width, height, true);
+    let max_side = (width.min(height) / 3).max(3);
+    let mut rooms: Vec<(usize, usize, usize, usize)> = Vec::new();
+    let mut covered = 0;
+    for _ in 0..MAX_ATTEMPTS {
+        if covered as f32 >= density * grid.inner_area() as f32 {
+            break;
+        }
+
+        let (w, h) = (rng.gen_range(3..=max_side), rng.gen_range(3..=max_side));
+        if w + 2 > width || h + 2 > height {
+            continue;
+        }
+        let (x, z) = (
+            rng.gen_range(1..=width - w - 1),
+            rng.gen_range(1..=height - h - 1),
+        );
+        // Rooms keep a wall between each other
+        let overlaps = rooms
+            .iter()
+            .any(|(rx, rz, rw, rh)| x <= rx + rw && *rx <= x + w && z <= rz + rh && *rz <= z + h);
+        if overlaps {
+            continue;
+        }
+
+        for rz in z..z + h {
+            for rx in x..x + w {
+                grid.set(rx, rz, false);
+            }
+        }
+        if let Some((px, pz, pw, ph)) = rooms.last() {
+            let (from, to) = ((px + pw / 2, pz + ph / 2), (x + w / 2, z + h / 2));
+            for cx in from.0.min(to.0)..=from.0.max(to.0) {
+                grid.set(cx, from.1, false);
+            }
+            for cz in from.1.min(to.1)..=from.1.max(to.1) {
+                grid.set(to.0, cz, false);
+            }
+        }
+        rooms.push((x, z, w, h));
+        covered += w * h;
+    }
+
+    grid
+}
+
+/// Open floor with blocks of cover taking up about `density` of it
+fn arena(width: usize, height: usize, density: f32, rng: &mut StdRng) -> Grid {
+    let mut grid = Grid::new(width, height, false);
+    let mut covered = 0;
+    for _ in 0..MAX_ATTEMPTS {
+        if covered as f32 >= density * grid.inner_area() as f32 {
+            break;
+        }
+
+        let (w, h) = (rng.gen_range(1..=3), rng.gen_range(1..=3));
+        if w + 2 > width || h + 2 > height {
+            continue;
+        }
+        let (x, z) = (
+            rng.gen_range(1..=width - w - 1),
+            rng.gen_range(1..=height - h - 1),
+        );
+        for bz in z..z + h {
+            for bx in x..x + w {
+                if !grid.is_wall(bx, bz) {
+                    grid.set(bx, bz, true);
+                    covered += 1;
+                }
+            }
+        }
+    }
+
+    grid
+}
+
+/// Generates a map, the same config and seed always give the same map
+pub(crate) fn generate(config: &GeneratorConfig, seed: u64) -> GameMap {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let density = config.density();
+    let mut grid = match config.kind {
+        GeneratorKind::Maze => maze(config.width, config.height, density, &mut rng),
+        GeneratorKind::Dungeon => dungeon(config.width, config.height, density, &mut rng),
+        GeneratorKind::Arena => arena(config.width, config.height, density, &mut rng),
+    };
+    grid.keep_largest_region();
+    grid.into_game_map()
+}
+
+// -------
+// Systems
+// -------
+
+/// Replaces the map at the start of every episode, seeded like the startup map
+pub(crate) fn regenerate_map(
+    config: Res<GameConfig>,
+    rng: Res<GameRng>,
+    mut game_map: ResMut<GameMap>,
+) {
+    let Some(generator) = config.generator.as_ref().filter(|g| g.regenerate) else {
+        return;
+    };
+
+    let generated = generate(generator, generator.map_seed(rng.seed));
+    match config.check_map(&generated) {
+        Ok(()) => *game_map = generated,
+        Err(err) => error!("keeping the previous map, generated map is invalid: {err}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KINDS: [GeneratorKind; 3] = [
+        GeneratorKind::Maze,
+        GeneratorKind::Dungeon,
+        GeneratorKind::Arena,
+    ];
+
+    fn generator(kind: GeneratorKind) -> GeneratorConfig {
+        GeneratorConfig { kind, ..default() }
+    }
+
+    #[test]
+    fn same_seed_same_map() {
+        for kind in KINDS {
+            let generator = generator(kind);
+            let first = generate(&generator, 7).to_text().unwrap();
+            assert_eq!(
+                generate(&generator, 7).to_text().unwrap(),
+                first,
+                "{kind:?}"
+            );
+            assert_ne!(
+                generate(&generator, 8).to_text().unwrap(),
+                first,
+                "{kind:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn default_density_maps_are_valid() {
+        let config = GameConfig::default();
+        for kind in KINDS {
+            for seed in 0..8 {
+                let game_map = generate(&generator(kind), seed);
+                assert_eq!(config.check_map(&game_map), Ok(()), "{kind:?}, seed {seed}");
+            }
+        }
+    }
+}
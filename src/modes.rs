@@ -0,0 +1,331 @@
+use std::{collections::HashMap, fs::File};
+
+use bevy::{app::AppExit, input::mouse::MouseMotion, prelude::*};
+use bevy_rapier3d::prelude::*;
+use bevy_rl::*;
+use clap::ValueEnum;
+use rand::prelude::SliceRandom;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    actions::*, actors::*, config::*, control::*, events::*, game::*, game_mode::*, level::*,
+    observations::*, rewards::*, weapons::*,
+};
+
+/// Seed used by `eval` mode when `--seed` is not given, so evaluation runs are comparable
+pub(crate) const EVAL_SEED: u64 = 0;
+
+const SPECTATOR_SPEED: f32 = 30.0;
+const SPECTATOR_SENSITIVITY: f32 = 0.003;
+
+const BOT_ACTIONS: [Actions; 7] = [
+    Actions::FORWARD,
+    Actions::BACKWARD,
+    Actions::LEFT,
+    Actions::RIGHT,
+    Actions::TURN_LEFT,
+    Actions::TURN_RIGHT,
+    Actions::SHOOT,
+];
+
+#[derive(
+    ValueEnum, Resource, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq,
+)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum RunMode {
+    /// All agents are controlled through the bevy_rl REST API
+    #[default]
+    Train,
+    /// Agent 0 is driven with keyboard and mouse, other agents are bots
+    Play,
+    /// Free-fly observer camera over a bot match
+    Spectate,
+    /// REST-controlled evaluation with a fixed seed, writes a summary and exits
+    Eval,
+}
+
+impl RunMode {
+    /// Agents are stepped locally instead of waiting for bevy_rl control requests
+    pub(crate) fn is_local(&self) -> bool {
+        matches!(self, RunMode::Play | RunMode::Spectate)
+    }
+}
+
+// ----------
+// Components
+// ----------
+
+/// Marks the actor driven by keyboard and mouse in `play` mode
+#[derive(Component)]
+pub(crate) struct PlayerControlled;
+
+#[derive(Component)]
+pub(crate) struct SpectatorCamera;
+
+// ---------
+// Resources
+// ---------
+
+#[derive(Default, Serialize, Clone)]
+pub(crate) struct EpisodeResult {
+    episode: u32,
+    duration: f32,
+    kills: HashMap<String, u32>,
+    survivors: Vec<String>,
+}
+
+#[derive(Resource, Default, Serialize)]
+pub(crate) struct EvalSummary {
+    episodes: Vec<EpisodeResult>,
+    #[serde(skip)]
+    current: EpisodeResult,
+    #[serde(skip)]
+    recorded: bool,
+}
+
+// -------------
+// Local control
+// -------------
+
+fn player_actions(keys: &Input<KeyCode>, mouse_buttons: &Input<MouseButton>) -> Actions {
+    let mut actions = Actions::IDLE;
+
+    if keys.pressed(KeyCode::W) {
+        actions |= Actions::FORWARD;
+    }
+    if keys.pressed(KeyCode::S) {
+        actions |= Actions::BACKWARD;
+    }
+    if keys.pressed(KeyCode::A) {
+        actions |= Actions::LEFT;
+    }
+    if keys.pressed(KeyCode::D) {
+        actions |= Actions::RIGHT;
+    }
+    if keys.pressed(KeyCode::Q) || keys.pressed(KeyCode::Left) {
+        actions |= Actions::TURN_LEFT;
+    }
+    if keys.pressed(KeyCode::E) || keys.pressed(KeyCode::Right) {
+        actions |= Actions::TURN_RIGHT;
+    }
+    if keys.pressed(KeyCode::Space) || mouse_buttons.pressed(MouseButton::Left) {
+        actions |= Actions::SHOOT;
+    }
+    // Once per key press, holding the key would cycle every tick
+    if keys.just_pressed(KeyCode::R) {
+        actions |= Actions::RELOAD;
+    }
+    if keys.just_pressed(KeyCode::Tab) {
+        actions |= Actions::NEXT_WEAPON;
+    }
+
+    actions
+}
+
+/// Replaces `bevy_rl_control_request` in `play` and `spectate` modes
+pub(crate) fn local_control_request(
+    keys: Res<Input<KeyCode>>,
+    mouse_buttons: Res<Input<MouseButton>>,
+    mut simulation_state: ResMut<NextState<SimulationState>>,
+    mut rapier_configuration: ResMut<RapierConfiguration>,
+    mut rng: ResMut<GameRng>,
+    mut step_rewards: ResMut<StepRewards>,
+    config: Res<GameConfig>,
+    players: Query<(&AgentId, Option<&PlayerControlled>)>,
+    query_actors: Query<(
+        Entity,
+        &mut Velocity,
+        &mut Transform,
+        &Actor,
+        &AgentId,
+        &mut Arsenal,
+        Option<&MoveSpeedScale>,
+    )>,
+    obstacles: Query<(), Or<(With<Wall>, With<Actor>)>>,
+    collision_events: EventReader<CollisionEvent>,
+    event_gun_shot: EventWriter<EventGunShot>,
+) {
+    step_rewards.clear();
+    let mut actions: Vec<Option<AgentAction>> = vec![None; config.num_agents as usize];
+    for (id, player) in players.iter() {
+        let action = match player {
+            Some(_) => Some(player_actions(&keys, &mouse_buttons)),
+            None => BOT_ACTIONS.choose(&mut rng.rng).cloned(),
+        };
+        actions[id.0] = action.map(AgentAction::Discrete);
+    }
+
+    control_agents(
+        actions,
+        &config,
+        query_actors,
+        obstacles,
+        collision_events,
+        event_gun_shot,
+    );
+    rapier_configuration.physics_pipeline_active = true;
+    simulation_state.set(SimulationState::Running);
+}
+
+/// Nobody calls the reset endpoint in local modes, so start a new round directly
+pub(crate) fn restart_local_round(
+    mut commands: Commands,
+    mut event_round_over_reader: EventReader<EventRoundOver>,
+    walls: Query<Entity, With<Wall>>,
+    players: Query<Entity, With<Actor>>,
+    projectiles: Query<Entity, With<Projectile>>,
+    mode_entities: Query<Entity, With<ModeEntity>>,
+    recent_shots: Option<ResMut<RecentShots>>,
+    mut simulation_state: ResMut<NextState<SimulationState>>,
+) {
+    if event_round_over_reader.iter().count() == 0 {
+        return;
+    }
+
+    for e in walls
+        .iter()
+        .chain(players.iter())
+        .chain(projectiles.iter())
+        .chain(mode_entities.iter())
+    {
+        commands.entity(e).despawn_recursive();
+    }
+    if let Some(mut recent_shots) = recent_shots {
+        recent_shots.shots.clear();
+    }
+
+    simulation_state.set(SimulationState::Initializing);
+}
+
+// ---------
+// Spectator
+// ---------
+
+pub(crate) fn spawn_spectator_camera(mut commands: Commands) {
+    commands.spawn((
+        Camera3dBundle {
+            transform: Transform::from_xyz(64.0, 80.0, 150.0)
+                .looking_at(Vec3::new(64.0, 0.0, 64.0), Vec3::Y),
+            ..default()
+        },
+        SpectatorCamera,
+    ));
+}
+
+/// WASD to fly, Space/Shift to go up and down, hold right mouse button to look around
+pub(crate) fn spectator_camera_movement(
+    time: Res<Time>,
+    keys: Res<Input<KeyCode>>,
+    mouse_buttons: Res<Input<MouseButton>>,
+    mut mouse_motion: EventReader<MouseMotion>,
+    mut cameras: Query<&mut Transform, With<SpectatorCamera>>,
+) {
+    let look: Vec2 = mouse_motion.iter().map(|m| m.delta).sum();
+
+    for mut transform in cameras.iter_mut() {
+        if mouse_buttons.pressed(MouseButton::Right) {
+            let (yaw, pitch, _) = transform.rotation.to_euler(EulerRot::YXZ);
+            let yaw = yaw - look.x * SPECTATOR_SENSITIVITY;
+            let pitch = (pitch - look.y * SPECTATOR_SENSITIVITY).clamp(-1.54, 1.54);
+            transform.rotation = Quat::from_euler(EulerRot::YXZ, yaw, pitch, 0.0);
+        }
+
+        let mut direction = Vec3::ZERO;
+        if keys.pressed(KeyCode::W) {
+            direction += transform.forward();
+        }
+        if keys.pressed(KeyCode::S) {
+            direction += transform.back();
+        }
+        if keys.pressed(KeyCode::A) {
+            direction += transform.left();
+        }
+        if keys.pressed(KeyCode::D) {
+            direction += transform.right();
+        }
+        if keys.pressed(KeyCode::Space) {
+            direction += Vec3::Y;
+        }
+        if keys.pressed(KeyCode::LShift) {
+            direction -= Vec3::Y;
+        }
+
+        transform.translation +=
+            direction.normalize_or_zero() * SPECTATOR_SPEED * time.delta_seconds();
+    }
+}
+
+// ----------
+// Evaluation
+// ----------
+
+pub(crate) fn eval_begin_episode(mut summary: ResMut<EvalSummary>) {
+    let episode = summary.episodes.len() as u32;
+    summary.current = EpisodeResult {
+        episode,
+        ..default()
+    };
+    summary.recorded = false;
+}
+
+pub(crate) fn eval_record_kills(
+    mut summary: ResMut<EvalSummary>,
+    mut event_death: EventReader<EventDeath>,
+    actors: Query<(&Actor, &AgentId)>,
+) {
+    for death in event_death.iter() {
+        let Some((killer, _)) = actors.iter().find(|(_, id)| **id == death.killer) else {
+            continue;
+        };
+
+        *summary
+            .current
+            .kills
+            .entry(killer.name.clone())
+            .or_default() += 1;
+    }
+}
+
+pub(crate) fn eval_record_episode(
+    mut summary: ResMut<EvalSummary>,
+    mut event_round_over_reader: EventReader<EventRoundOver>,
+    mut exit: EventWriter<AppExit>,
+    config: Res<GameConfig>,
+    actors: Query<&Actor>,
+) {
+    let Some(round_over) = event_round_over_reader.iter().last() else {
+        return;
+    };
+    if summary.recorded {
+        return;
+    }
+
+    let mut result = summary.current.clone();
+    result.duration = round_over.duration;
+    result.survivors = actors
+        .iter()
+        .filter(|a| a.health > 0)
+        .map(|a| a.name.clone())
+        .collect();
+
+    summary.episodes.push(result);
+    summary.recorded = true;
+
+    if summary.episodes.len() < config.episodes as usize {
+        return;
+    }
+
+    match File::create(&config.output) {
+        Ok(file) => {
+            if let Err(err) = serde_json::to_writer_pretty(file, &*summary) {
+                error!("failed to write eval summary: {err}");
+            }
+        }
+        Err(err) => error!(
+            "failed to create eval summary {}: {err}",
+            config.output.display()
+        ),
+    }
+
+    exit.send(AppExit);
+}
@@ -0,0 +1,484 @@
+use std::collections::HashSet;
+
+use bevy::prelude::*;
+use bevy_mod_raycast::RaycastSource;
+use bevy_rapier3d::prelude::*;
+use serde::Serialize;
+
+use crate::{actors::*, config::*, game::*, level::*, teams::*, weapons::*};
+
+/// How far a shot that hit nothing is traced in the occupancy shots channel
+pub(crate) const MAX_SHOT_TRACE: f32 = 100.0;
+
+/// Half of the wall cube side spawned by `spawn_game_world`
+const WALL_HALF_EXTENT: f32 = 1.0;
+
+/// Sampling step of `line_of_sight` in world units
+const LINE_OF_SIGHT_STEP: f32 = 0.25;
+
+// ----------
+// Components
+// ----------
+
+/// A single lidar ray, spawned as a child of its actor
+#[derive(Component)]
+pub(crate) struct LidarRay {
+    pub(crate) index: usize,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum HitType {
+    #[default]
+    Nothing,
+    Wall,
+    Enemy,
+    /// Another agent of the same team, never reported without teams
+    Teammate,
+}
+
+/// Latest lidar readings of an actor, one entry per ray ordered left to right.
+/// Rays that hit nothing within range report the maximum range.
+#[derive(Component, Serialize, Clone, Debug, Default)]
+pub(crate) struct LidarScan {
+    pub(crate) distances: Vec<f32>,
+    pub(crate) hits: Vec<HitType>,
+}
+
+impl LidarScan {
+    pub(crate) fn new(lidar: &LidarConfig) -> Self {
+        LidarScan {
+            distances: vec![lidar.max_range; lidar.rays as usize],
+            hits: vec![HitType::Nothing; lidar.rays as usize],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct RecentShot {
+    pub(crate) from: Vec3,
+    pub(crate) to: Vec3,
+    /// Round time the shot was fired at
+    pub(crate) time: f32,
+}
+
+/// Shots fired within the last `ttl` seconds of round time.
+/// Only present when occupancy observations are enabled.
+#[derive(Resource, Default)]
+pub(crate) struct RecentShots {
+    pub(crate) ttl: f32,
+    pub(crate) shots: Vec<RecentShot>,
+}
+
+impl RecentShots {
+    pub(crate) fn push(&mut self, shot: RecentShot) {
+        self.shots.retain(|s| is_recent(s, shot.time, self.ttl));
+        self.shots.push(shot);
+    }
+}
+
+/// Round timer restarts every round, shots "from the future" belong to a previous one
+fn is_recent(shot: &RecentShot, now: f32, ttl: f32) -> bool {
+    shot.time <= now && now - shot.time <= ttl
+}
+
+// ------------
+// Observations
+// ------------
+
+/// Per-agent feature vector built from the actor's own components
+#[derive(Serialize, Clone, Default, Debug)]
+pub(crate) struct Proprioception {
+    /// Fraction of starting health left
+    pub(crate) health: f32,
+    /// World-space velocity on the ground plane, `[x, z]`
+    pub(crate) velocity: [f32; 2],
+    /// Turning speed in radians per second, positive to the left
+    pub(crate) angular_velocity: f32,
+    pub(crate) heading_sin: f32,
+    pub(crate) heading_cos: f32,
+    /// Seconds left in the round
+    pub(crate) time_remaining: f32,
+    /// Whether the most recent shot hit another agent
+    pub(crate) last_shot_hit: bool,
+    /// Index of the selected weapon in the weapon catalog
+    pub(crate) weapon: usize,
+    /// Fraction of the magazine left
+    pub(crate) magazine: f32,
+    /// Fraction of the starting reserve ammo left
+    pub(crate) reserve: f32,
+    /// Seconds until the next shot can be fired, reloading aside
+    pub(crate) cooldown: f32,
+    /// Seconds until the reload in progress completes, 0 when not reloading
+    pub(crate) reload: f32,
+}
+
+impl Proprioception {
+    pub(crate) fn new(
+        actor: &Actor,
+        velocity: &Velocity,
+        transform: &Transform,
+        last_shot: &LastShot,
+        arsenal: &Arsenal,
+        starting_health: u16,
+        time_remaining: f32,
+    ) -> Self {
+        let (heading, _, _) = transform.rotation.to_euler(EulerRot::YXZ);
+        let weapon = arsenal.current();
+
+        Proprioception {
+            health: actor.health as f32 / starting_health as f32,
+            velocity: [velocity.linvel.x, velocity.linvel.z],
+            angular_velocity: velocity.angvel.y,
+            heading_sin: heading.sin(),
+            heading_cos: heading.cos(),
+            time_remaining,
+            last_shot_hit: last_shot.hit,
+            weapon: arsenal.selected,
+            magazine: weapon.magazine as f32 / weapon.stats().magazine_size as f32,
+            reserve: weapon.reserve as f32 / weapon.stats().reserve_ammo.max(1) as f32,
+            cooldown: weapon.cooldown,
+            reload: weapon.reload,
+        }
+    }
+}
+
+pub(crate) const OCCUPANCY_WALLS: usize = 0;
+pub(crate) const OCCUPANCY_SELF: usize = 1;
+pub(crate) const OCCUPANCY_ENEMIES: usize = 2;
+pub(crate) const OCCUPANCY_SHOTS: usize = 3;
+/// Last so that the other channels keep their index, empty without teams
+pub(crate) const OCCUPANCY_TEAMMATES: usize = 4;
+const OCCUPANCY_CHANNELS: usize = 5;
+
+/// Egocentric top-down map of an agent: the agent is in the middle of the grid and faces
+/// row 0, columns grow to its right.
+#[derive(Serialize, Clone, Debug)]
+pub(crate) struct OccupancyGrid {
+    /// `[channels, rows, columns]`, channels are walls, self, enemies, recent shots and
+    /// teammates
+    pub(crate) shape: [usize; 3],
+    /// Cells in row-major order packed 8 per byte, most significant bit first
+    /// (`numpy.unpackbits` order)
+    pub(crate) data: Vec<u8>,
+}
+
+impl OccupancyGrid {
+    fn new(size: usize) -> Self {
+        let cells = OCCUPANCY_CHANNELS * size * size;
+        OccupancyGrid {
+            shape: [OCCUPANCY_CHANNELS, size, size],
+            data: vec![0; (cells + 7) / 8],
+        }
+    }
+
+    fn set(&mut self, channel: usize, (row, column): (usize, usize)) {
+        let bit = (channel * self.shape[1] + row) * self.shape[2] + column;
+        self.data[bit / 8] |= 0x80 >> (bit % 8);
+    }
+}
+
+/// Maps between world positions and cells of an agent's grid
+struct EgocentricFrame {
+    origin: Vec3,
+    rotation: Quat,
+    cell_size: f32,
+    size: usize,
+}
+
+impl EgocentricFrame {
+    fn new(transform: &Transform, occupancy: &OccupancyConfig) -> Self {
+        let (yaw, _, _) = transform.rotation.to_euler(EulerRot::YXZ);
+        EgocentricFrame {
+            origin: transform.translation * Vec3::new(1.0, 0.0, 1.0),
+            rotation: Quat::from_rotation_y(yaw),
+            cell_size: occupancy.cell_size,
+            size: occupancy.size as usize,
+        }
+    }
+
+    fn cell(&self, world: Vec3) -> Option<(usize, usize)> {
+        // Forward is -Z, so positions ahead of the agent land in the first rows
+        let local = self.rotation.inverse() * (world * Vec3::new(1.0, 0.0, 1.0) - self.origin);
+        let half = self.size as f32 / 2.0;
+        let row = (local.z / self.cell_size + half).floor();
+        let column = (local.x / self.cell_size + half).floor();
+
+        let range = 0.0..self.size as f32;
+        (range.contains(&row) && range.contains(&column)).then_some((row as usize, column as usize))
+    }
+
+    fn center(&self, (row, column): (usize, usize)) -> Vec3 {
+        let half = self.size as f32 / 2.0;
+        let local = Vec3::new(
+            (column as f32 + 0.5 - half) * self.cell_size,
+            0.0,
+            (row as f32 + 0.5 - half) * self.cell_size,
+        );
+        self.origin + self.rotation * local
+    }
+}
+
+/// Map cells occupied by live walls, keyed by the wall cell center
+pub(crate) fn wall_cells<'a>(walls: impl Iterator<Item = &'a Wall>) -> HashSet<(i32, i32)> {
+    walls
+        .flat_map(|wall| wall.cells.iter())
+        .map(|(x, z)| (*x as i32, *z as i32))
+        .collect()
+}
+
+fn is_wall(walls: &HashSet<(i32, i32)>, position: Vec3) -> bool {
+    let mut xs = (position.x - WALL_HALF_EXTENT).ceil() as i32
+        ..=(position.x + WALL_HALF_EXTENT).floor() as i32;
+    let zs = (position.z - WALL_HALF_EXTENT).ceil() as i32
+        ..=(position.z + WALL_HALF_EXTENT).floor() as i32;
+
+    xs.any(|x| zs.clone().any(|z| walls.contains(&(x, z))))
+}
+
+/// Whether no wall cell lies on the ground-plane segment between `from` and `to`, `walls`
+/// comes from `wall_cells`
+pub(crate) fn line_of_sight(walls: &HashSet<(i32, i32)>, from: Vec3, to: Vec3) -> bool {
+    let steps = ((from.distance(to) / LINE_OF_SIGHT_STEP).ceil() as usize).max(1);
+
+    (0..=steps).all(|i| {
+        // Walls are wider than the spacing of their cells, so look around each sample
+        !is_wall(walls, from.lerp(to, i as f32 / steps as f32))
+    })
+}
+
+/// Build an occupancy grid for every actor. `actors` holds each actor's transform, whether
+/// it is alive and its team, `walls` comes from `wall_cells`.
+pub(crate) fn occupancy_grids(
+    occupancy: &OccupancyConfig,
+    actors: &[(&Transform, bool, Option<Team>)],
+    walls: &HashSet<(i32, i32)>,
+    recent_shots: &RecentShots,
+    now: f32,
+) -> Vec<OccupancyGrid> {
+    let size = occupancy.size as usize;
+    let shots: Vec<&RecentShot> = recent_shots
+        .shots
+        .iter()
+        .filter(|s| is_recent(s, now, recent_shots.ttl))
+        .collect();
+
+    actors
+        .iter()
+        .enumerate()
+        .map(|(i, (transform, _, team))| {
+            let frame = EgocentricFrame::new(transform, occupancy);
+            let mut grid = OccupancyGrid::new(size);
+
+            for row in 0..size {
+                for column in 0..size {
+                    if is_wall(walls, frame.center((row, column))) {
+                        grid.set(OCCUPANCY_WALLS, (row, column));
+                    }
+                }
+            }
+
+            if let Some(cell) = frame.cell(transform.translation) {
+                grid.set(OCCUPANCY_SELF, cell);
+            }
+
+            for (j, (other, alive, other_team)) in actors.iter().enumerate() {
+                if j == i || !alive {
+                    continue;
+                }
+                let channel = if team.is_some() && other_team == team {
+                    OCCUPANCY_TEAMMATES
+                } else {
+                    OCCUPANCY_ENEMIES
+                };
+                if let Some(cell) = frame.cell(other.translation) {
+                    grid.set(channel, cell);
+                }
+            }
+
+            // Sample every shot trace at half a cell so no crossed cell is skipped
+            for shot in shots.iter() {
+                let steps = (shot.from.distance(shot.to) / (occupancy.cell_size * 0.5)).ceil();
+                for step in 0..=steps as usize {
+                    let position = shot.from.lerp(shot.to, step as f32 / steps.max(1.0));
+                    if let Some(cell) = frame.cell(position) {
+                        grid.set(OCCUPANCY_SHOTS, cell);
+                    }
+                }
+            }
+
+            grid
+        })
+        .collect()
+}
+
+// -------
+// Systems
+// -------
+
+pub(crate) fn update_lidar_scans(
+    config: Res<GameConfig>,
+    rays: Query<(&Parent, &LidarRay, &RaycastSource<RaycastMarker>)>,
+    walls: Query<(), With<Wall>>,
+    parents: Query<&Parent>,
+    actors: Query<&Actor>,
+    mut scans: Query<&mut LidarScan>,
+) {
+    let Some(lidar) = &config.observations.lidar else {
+        return;
+    };
+
+    for (owner, ray, source) in rays.iter() {
+        let Ok(mut scan) = scans.get_mut(owner.get()) else {
+            continue;
+        };
+        let team = actors.get(owner.get()).ok().and_then(|actor| actor.team);
+
+        let hit = source
+            .intersections()
+            .iter()
+            .find_map(|(entity, intersection)| {
+                if walls.contains(*entity) {
+                    return Some((intersection.distance(), HitType::Wall));
+                }
+
+                // Agent models are children of the actor; ignore own and dead bodies
+                let body_owner = parents.get(*entity).ok()?.get();
+                if body_owner == owner.get() {
+                    return None;
+                }
+                match actors.get(body_owner) {
+                    Ok(actor) if actor.health > 0 && team.is_some() && actor.team == team => {
+                        Some((intersection.distance(), HitType::Teammate))
+                    }
+                    Ok(actor) if actor.health > 0 => {
+                        Some((intersection.distance(), HitType::Enemy))
+                    }
+                    _ => None,
+                }
+            })
+            .filter(|(distance, _)| *distance <= lidar.max_range)
+            .unwrap_or((lidar.max_range, HitType::Nothing));
+
+        scan.distances[ray.index] = hit.0;
+        scan.hits[ray.index] = hit.1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::FRAC_PI_2;
+
+    use super::*;
+
+    /// 8 by 8 cells of one world unit, the agent's own cell is (4, 4)
+    fn occupancy() -> OccupancyConfig {
+        OccupancyConfig {
+            size: 8,
+            cell_size: 1.0,
+            shot_ttl: 1.0,
+        }
+    }
+
+    fn is_set(grid: &OccupancyGrid, channel: usize, (row, column): (usize, usize)) -> bool {
+        let bit = (channel * grid.shape[1] + row) * grid.shape[2] + column;
+        grid.data[bit / 8] & (0x80 >> (bit % 8)) != 0
+    }
+
+    /// Grid of an agent at the origin with `rotation`, `others` are alive or dead agents
+    fn grid(
+        rotation: Quat,
+        team: Option<Team>,
+        walls: &HashSet<(i32, i32)>,
+        others: &[(Vec3, bool, Option<Team>)],
+    ) -> OccupancyGrid {
+        let agent = Transform::from_rotation(rotation);
+        let transforms: Vec<Transform> = others
+            .iter()
+            .map(|(position, ..)| Transform::from_translation(*position))
+            .collect();
+        let mut actors = vec![(&agent, true, team)];
+        for (transform, (_, alive, team)) in transforms.iter().zip(others) {
+            actors.push((transform, *alive, *team));
+        }
+        let shots = RecentShots::default();
+        occupancy_grids(&occupancy(), &actors, walls, &shots, 0.0).remove(0)
+    }
+
+    #[test]
+    fn cells_are_packed_most_significant_bit_first() {
+        let mut grid = OccupancyGrid::new(4);
+        assert_eq!(grid.shape, [OCCUPANCY_CHANNELS, 4, 4]);
+        assert_eq!(grid.data.len(), 10);
+
+        grid.set(OCCUPANCY_WALLS, (0, 0));
+        grid.set(OCCUPANCY_WALLS, (0, 1));
+        grid.set(OCCUPANCY_WALLS, (2, 1));
+        grid.set(OCCUPANCY_SELF, (0, 0));
+        assert_eq!(grid.data[..3], [0b1100_0000, 0b0100_0000, 0b1000_0000]);
+        assert!(grid.data[3..].iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn ahead_is_row_zero_and_right_is_the_last_column() {
+        let walls = HashSet::from([(0, -4)]);
+        let others = [
+            (Vec3::new(0.5, 1.0, -2.5), true, None),
+            (Vec3::new(2.5, 1.0, 0.5), true, None),
+        ];
+        let grid = grid(Quat::IDENTITY, None, &walls, &others);
+
+        assert!(is_set(&grid, OCCUPANCY_SELF, (4, 4)));
+        assert!(is_set(&grid, OCCUPANCY_ENEMIES, (1, 4)));
+        assert!(is_set(&grid, OCCUPANCY_ENEMIES, (4, 6)));
+        // The wall cube spans x in [-1, 1] and z in [-5, -3]
+        assert!(is_set(&grid, OCCUPANCY_WALLS, (0, 3)));
+        assert!(is_set(&grid, OCCUPANCY_WALLS, (0, 4)));
+        assert!(!is_set(&grid, OCCUPANCY_WALLS, (0, 5)));
+        assert!(!is_set(&grid, OCCUPANCY_WALLS, (1, 4)));
+    }
+
+    #[test]
+    fn grid_turns_with_the_agent() {
+        // Facing +x, so +z is to the right
+        let others = [(Vec3::new(2.5, 1.0, 0.5), true, None)];
+        let grid = grid(
+            Quat::from_rotation_y(-FRAC_PI_2),
+            None,
+            &HashSet::new(),
+            &others,
+        );
+        assert!(is_set(&grid, OCCUPANCY_ENEMIES, (1, 4)));
+    }
+
+    #[test]
+    fn walls_block_line_of_sight_over_their_full_extent() {
+        // The wall cube spans x in [3, 5] and z in [-1, 1]
+        let walls = HashSet::from([(4, 0)]);
+        let sight = |z: f32| line_of_sight(&walls, Vec3::new(0.0, 1.0, z), Vec3::new(8.0, 1.0, z));
+
+        assert!(!sight(0.0));
+        assert!(!sight(0.9));
+        assert!(sight(2.0));
+        assert!(line_of_sight(
+            &walls,
+            Vec3::new(0.0, 1.0, 0.0),
+            Vec3::new(2.5, 1.0, 0.0)
+        ));
+    }
+
+    #[test]
+    fn teammates_and_dead_agents() {
+        let others = [
+            (Vec3::new(0.5, 1.0, -2.5), true, Some(Team(0))),
+            (Vec3::new(2.5, 1.0, 0.5), true, Some(Team(1))),
+            (Vec3::new(-2.5, 1.0, 0.5), false, Some(Team(1))),
+        ];
+        let grid = grid(Quat::IDENTITY, Some(Team(0)), &HashSet::new(), &others);
+
+        assert!(is_set(&grid, OCCUPANCY_TEAMMATES, (1, 4)));
+        assert!(!is_set(&grid, OCCUPANCY_ENEMIES, (1, 4)));
+        assert!(is_set(&grid, OCCUPANCY_ENEMIES, (4, 6)));
+        assert!(!is_set(&grid, OCCUPANCY_ENEMIES, (4, 1)));
+    }
+}
@@ -0,0 +1,279 @@
+use crate::config::*;
+use bevy::{
+    asset::load_internal_asset,
+    core_pipeline::{
+        clear_color::ClearColorConfig,
+        tonemapping::{DebandDither, Tonemapping},
+    },
+    prelude::*,
+    reflect::TypeUuid,
+    render::{
+        camera::{RenderTarget, Viewport},
+        render_resource::{AsBindGroup, ShaderRef},
+        view::RenderLayers,
+    },
+};
+
+const DEPTH_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 0x5f3b_2c1e_9a47_d801);
+
+/// Extra passes only see the copies of meshes made for them
+const DEPTH_LAYER: u8 = 1;
+const SEGMENTATION_LAYER: u8 = 2;
+/// Every agent's segmentation camera also sees a layer of its own, starting here, which holds
+/// its `Myself` copy; its `Enemy` copy is on the layers of all the other agents
+const FIRST_AGENT_LAYER: u8 = 3;
+/// Agents that fit in the render layers left for them, see `spawn_agent_copies`
+pub(crate) const MAX_SEGMENTATION_AGENTS: u32 =
+    RenderLayers::TOTAL_LAYERS as u32 - FIRST_AGENT_LAYER as u32;
+
+fn agent_layer(agent: usize) -> u8 {
+    FIRST_AGENT_LAYER + agent as u8
+}
+
+/// A strip of an agent's render image. Passes are laid out left to right in declaration
+/// order, each `width` pixels wide.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum VisualPass {
+    Color,
+    Depth,
+    Segmentation,
+}
+
+/// Semantic classes of the segmentation pass; pixels that see nothing stay white
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum SegmentationClass {
+    Floor,
+    Wall,
+    Enemy,
+    /// Only visible when a camera sees its own agent's body
+    Myself,
+    Flag,
+    Hill,
+}
+
+impl SegmentationClass {
+    const ALL: [SegmentationClass; 6] = [
+        SegmentationClass::Floor,
+        SegmentationClass::Wall,
+        SegmentationClass::Enemy,
+        SegmentationClass::Myself,
+        SegmentationClass::Flag,
+        SegmentationClass::Hill,
+    ];
+
+    fn color(&self) -> Color {
+        match self {
+            SegmentationClass::Floor => Color::rgb(0.0, 0.0, 1.0),
+            SegmentationClass::Wall => Color::rgb(0.0, 1.0, 0.0),
+            SegmentationClass::Enemy => Color::rgb(1.0, 0.0, 0.0),
+            SegmentationClass::Myself => Color::rgb(1.0, 1.0, 0.0),
+            SegmentationClass::Flag => Color::rgb(1.0, 0.0, 1.0),
+            SegmentationClass::Hill => Color::rgb(1.0, 0.5, 0.0),
+        }
+    }
+}
+
+/// Distance to the camera divided by `max_depth`, written as a grayscale value
+#[derive(AsBindGroup, TypeUuid, Debug, Clone)]
+#[uuid = "2f0f7a43-6e0e-4d5a-9a3e-3c2b6f4e9d11"]
+pub(crate) struct DepthMaterial {
+    #[uniform(0)]
+    max_depth: f32,
+}
+
+impl Material for DepthMaterial {
+    fn fragment_shader() -> ShaderRef {
+        DEPTH_SHADER_HANDLE.typed().into()
+    }
+}
+
+/// Extra-pass copy of an agent's body. Agents are hidden, so their copies are made visible on
+/// their own and follow the agent's health instead, see `sync_agent_copies`.
+#[derive(Component)]
+pub(crate) struct AgentCopy;
+
+/// Materials of the enabled extra passes; only present when any of them is enabled
+#[derive(Resource)]
+pub(crate) struct PassMaterials {
+    depth: Option<Handle<DepthMaterial>>,
+    segmentation: Option<Vec<Handle<StandardMaterial>>>,
+}
+
+impl PassMaterials {
+    /// Spawn copies of a mesh for every extra pass as children of `parent`
+    pub(crate) fn spawn_copies(
+        &self,
+        parent: &mut ChildBuilder,
+        mesh: &Handle<Mesh>,
+        transform: Transform,
+        class: SegmentationClass,
+    ) {
+        if let Some(material) = &self.depth {
+            parent.spawn((
+                MaterialMeshBundle {
+                    mesh: mesh.clone(),
+                    material: material.clone(),
+                    transform,
+                    ..default()
+                },
+                RenderLayers::layer(DEPTH_LAYER),
+            ));
+        }
+        if let Some(materials) = &self.segmentation {
+            parent.spawn((
+                PbrBundle {
+                    mesh: mesh.clone(),
+                    material: materials[class as usize].clone(),
+                    transform,
+                    ..default()
+                },
+                RenderLayers::layer(SEGMENTATION_LAYER),
+            ));
+        }
+    }
+
+    /// Spawn copies of agent `agent`'s body for every extra pass as children of `parent`. Its
+    /// own segmentation camera sees it as `Myself`, the other agents' cameras as `Enemy`.
+    pub(crate) fn spawn_agent_copies(
+        &self,
+        parent: &mut ChildBuilder,
+        mesh: &Handle<Mesh>,
+        transform: Transform,
+        agent: usize,
+        num_agents: usize,
+    ) {
+        if let Some(material) = &self.depth {
+            parent.spawn((
+                MaterialMeshBundle {
+                    mesh: mesh.clone(),
+                    material: material.clone(),
+                    transform,
+                    visibility: Visibility::Visible,
+                    ..default()
+                },
+                RenderLayers::layer(DEPTH_LAYER),
+                AgentCopy,
+            ));
+        }
+        if let Some(materials) = &self.segmentation {
+            let others: Vec<u8> = (0..num_agents)
+                .filter(|other| *other != agent)
+                .map(agent_layer)
+                .collect();
+            for (class, layers) in [
+                (
+                    SegmentationClass::Myself,
+                    RenderLayers::layer(agent_layer(agent)),
+                ),
+                (SegmentationClass::Enemy, RenderLayers::from_layers(&others)),
+            ] {
+                parent.spawn((
+                    PbrBundle {
+                        mesh: mesh.clone(),
+                        material: materials[class as usize].clone(),
+                        transform,
+                        visibility: Visibility::Visible,
+                        ..default()
+                    },
+                    layers,
+                    AgentCopy,
+                ));
+            }
+        }
+    }
+}
+
+/// Viewport of pass `index` inside an agent's render image
+pub(crate) fn pass_viewport(index: usize, width: u32, height: u32) -> Viewport {
+    Viewport {
+        physical_position: UVec2::new(index as u32 * width, 0),
+        physical_size: UVec2::new(width, height),
+        ..default()
+    }
+}
+
+/// Camera for an extra pass of agent `agent`. The color camera renders first and clears the
+/// whole image, extra passes draw over it so that pixels that see nothing stay white.
+pub(crate) fn new_pass_camera_bundle(
+    pass: VisualPass,
+    render_target: RenderTarget,
+    viewport: Viewport,
+    order: isize,
+    agent: usize,
+) -> (Camera3dBundle, RenderLayers) {
+    let layers = match pass {
+        VisualPass::Depth => RenderLayers::layer(DEPTH_LAYER),
+        VisualPass::Segmentation => {
+            RenderLayers::from_layers(&[SEGMENTATION_LAYER, agent_layer(agent)])
+        }
+        VisualPass::Color => RenderLayers::layer(0),
+    };
+
+    (
+        Camera3dBundle {
+            camera: Camera {
+                target: render_target,
+                viewport: Some(viewport),
+                order,
+                ..default()
+            },
+            camera_3d: Camera3d {
+                clear_color: ClearColorConfig::None,
+                ..default()
+            },
+            // Keep class colors and depth values exact
+            tonemapping: Tonemapping::None,
+            dither: DebandDither::Disabled,
+            ..default()
+        },
+        layers,
+    )
+}
+
+fn setup_pass_materials(
+    mut commands: Commands,
+    config: Res<GameConfig>,
+    mut depth_materials: ResMut<Assets<DepthMaterial>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    let depth = config.observations.depth.as_ref().map(|depth| {
+        depth_materials.add(DepthMaterial {
+            max_depth: depth.max_depth,
+        })
+    });
+    let segmentation = config.observations.segmentation.then(|| {
+        SegmentationClass::ALL
+            .iter()
+            .map(|class| {
+                materials.add(StandardMaterial {
+                    base_color: class.color(),
+                    unlit: true,
+                    ..default()
+                })
+            })
+            .collect()
+    });
+
+    commands.insert_resource(PassMaterials {
+        depth,
+        segmentation,
+    });
+}
+
+/// Depth and segmentation passes of the agent cameras
+pub(crate) struct RenderPassesPlugin;
+
+impl Plugin for RenderPassesPlugin {
+    fn build(&self, app: &mut App) {
+        load_internal_asset!(
+            app,
+            DEPTH_SHADER_HANDLE,
+            "shaders/depth.wgsl",
+            Shader::from_wgsl
+        );
+
+        app.add_plugin(MaterialPlugin::<DepthMaterial>::default())
+            .add_startup_system(setup_pass_materials);
+    }
+}
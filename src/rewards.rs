@@ -0,0 +1,175 @@
+use bevy::prelude::*;
+use bevy_rl::*;
+
+use crate::{actions::*, actors::*, config::*, events::*, gym::EnvironmentState};
+
+// ---------
+// Resources
+// ---------
+
+/// Reward collected by every agent since the last control request, indexed by `AgentId`
+#[derive(Resource, Default)]
+pub(crate) struct StepRewards(Vec<f32>);
+
+impl StepRewards {
+    /// Start collecting rewards for a new step
+    pub(crate) fn clear(&mut self) {
+        self.0.iter_mut().for_each(|r| *r = 0.0);
+    }
+
+    pub(crate) fn add(&mut self, id: AgentId, reward: f32) {
+        if self.0.len() <= id.0 {
+            self.0.resize(id.0 + 1, 0.0);
+        }
+        self.0[id.0] += reward;
+    }
+}
+
+// -------
+// Systems
+// -------
+
+/// Turns game events into rewards weighted by `RewardConfig`. Damage and kills only pay off
+/// against enemies, teammates share `team_*` rewards. Game modes add their own rewards before.
+pub(crate) fn apply_rewards(
+    config: Res<GameConfig>,
+    mut step_rewards: ResMut<StepRewards>,
+    mut event_hit: EventReader<EventHit>,
+    mut event_death: EventReader<EventDeath>,
+    mut event_shot_fired: EventReader<EventShotFired>,
+    mut event_round_over: EventReader<EventRoundOver>,
+    actors: Query<(&Actor, &AgentId)>,
+    ai_gym_state: Res<AIGymState<Actions, EnvironmentState>>,
+) {
+    let weights = &config.rewards;
+    let team_of = |agent: AgentId| {
+        actors
+            .iter()
+            .find(|(_, id)| **id == agent)
+            .and_then(|(actor, _)| actor.team)
+    };
+    let enemies = |a: AgentId, b: AgentId| team_of(a).is_none() || team_of(a) != team_of(b);
+    let teammates_of = |agent: AgentId| {
+        let team = team_of(agent);
+        actors
+            .iter()
+            .filter(move |(actor, id)| team.is_some() && actor.team == team && **id != agent)
+            .map(|(_, id)| *id)
+    };
+
+    for hit in event_hit.iter() {
+        let damage = hit.damage as f32;
+        if enemies(hit.from, hit.to) {
+            step_rewards.add(hit.from, weights.damage_dealt * damage);
+        }
+        step_rewards.add(hit.to, weights.damage_taken * damage);
+    }
+
+    for death in event_death.iter() {
+        if enemies(death.killer, death.victim) {
+            step_rewards.add(death.killer, weights.kill);
+            for teammate in teammates_of(death.killer) {
+                step_rewards.add(teammate, weights.team_kill);
+            }
+        }
+        step_rewards.add(death.victim, weights.death);
+        for teammate in teammates_of(death.victim) {
+            step_rewards.add(teammate, weights.team_death);
+        }
+    }
+
+    for round_over in event_round_over.iter() {
+        let Some(winner) = round_over.winner else {
+            continue;
+        };
+        for (actor, id) in actors.iter() {
+            if actor.team == Some(winner) {
+                step_rewards.add(*id, weights.team_win);
+            } else {
+                step_rewards.add(*id, weights.team_loss);
+            }
+        }
+    }
+
+    for shot in event_shot_fired.iter() {
+        step_rewards.add(shot.from, weights.shot_fired);
+        if shot.outcome != ShotOutcome::HitAgent {
+            step_rewards.add(shot.from, weights.missed_shot);
+        }
+        if shot.outcome == ShotOutcome::DestroyedWall {
+            step_rewards.add(shot.from, weights.wall_destroyed);
+        }
+    }
+
+    for (actor, id) in actors.iter() {
+        if actor.health > 0 {
+            step_rewards.add(*id, weights.survival_per_tick);
+        }
+    }
+
+    let mut ai_gym_state = ai_gym_state.lock().unwrap();
+    for (i, reward) in step_rewards.0.iter().enumerate() {
+        ai_gym_state.set_reward(i, *reward);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{game::*, game_mode::*, teams::*};
+
+    #[test]
+    fn finished_round_pays_team_win_once() {
+        let config = GameConfig {
+            num_agents: 2,
+            teams: Some(TeamConfig {
+                sizes: vec![1, 1],
+                ..default()
+            }),
+            rewards: RewardConfig {
+                team_win: 1.0,
+                team_loss: -1.0,
+                ..default()
+            },
+            ..default()
+        };
+
+        let mut app = App::new();
+        app.insert_resource(AIGymState::<Actions, EnvironmentState>::new(
+            config.gym_settings(),
+        ))
+        .insert_resource(RoundTimer(Timer::from_seconds(60.0, TimerMode::Repeating)))
+        .insert_resource(ModeStatus {
+            finished: true,
+            leader: Some(Team(0)),
+            ..default()
+        })
+        .insert_resource(config)
+        .init_resource::<Time>()
+        .init_resource::<StepRewards>()
+        .add_event::<EventHit>()
+        .add_event::<EventDeath>()
+        .add_event::<EventShotFired>()
+        .add_event::<EventRoundOver>()
+        .add_systems((check_termination, apply_rewards).chain());
+        for id in 0..2 {
+            app.world.spawn((
+                Actor {
+                    id: AgentId(id),
+                    team: Some(Team(id)),
+                    position: (0.0, 0.0),
+                    rotation: 0.0,
+                    name: format!("agent {id}"),
+                    health: 100,
+                },
+                AgentId(id),
+            ));
+        }
+
+        for _ in 0..3 {
+            app.update();
+        }
+
+        assert_eq!(app.world.resource::<StepRewards>().0, vec![1.0, -1.0]);
+    }
+}
@@ -0,0 +1,122 @@
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use bevy::prelude::*;
+
+/// Where `POST /seed` is served, next to the bevy_rl API on port 7878
+pub(crate) const SEED_ADDRESS: &str = "127.0.0.1:7879";
+
+/// A seed is a `u64`, longer bodies are cut off and fail to parse
+const MAX_SEED_BODY: usize = 64;
+
+// ---------
+// Resources
+// ---------
+
+/// Seed staged for the next episode through `POST /seed`, applied by the next reset
+#[derive(Resource, Clone, Default)]
+pub(crate) struct SeedRequest(Arc<Mutex<Option<u64>>>);
+
+impl SeedRequest {
+    /// Serves `POST /seed` on a background thread. The body is the seed in decimal; a valid
+    /// seed is answered with 200 and replaces any seed staged before, anything else with 400.
+    pub(crate) fn listen(&self, address: &str) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(address)?;
+        let local_address = listener.local_addr()?;
+        let staged = self.0.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                if let Err(err) = respond(stream, &staged) {
+                    warn!("seed request failed: {err}");
+                }
+            }
+        });
+        Ok(local_address)
+    }
+
+    /// Seed staged since the last reset, if any
+    pub(crate) fn take(&self) -> Option<u64> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+fn parse_seed(body: &str) -> Result<u64, String> {
+    let body = body.trim();
+    body.parse()
+        .map_err(|err| format!("invalid seed {body:?}: {err}"))
+}
+
+fn respond(mut stream: TcpStream, staged: &Mutex<Option<u64>>) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header)?;
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+    let mut body = vec![0; content_length.min(MAX_SEED_BODY)];
+    reader.read_exact(&mut body)?;
+
+    let mut request = request_line.split_whitespace();
+    let (status, message) = match (request.next(), request.next()) {
+        (Some("POST"), Some("/seed")) => match parse_seed(&String::from_utf8_lossy(&body)) {
+            Ok(seed) => {
+                *staged.lock().unwrap() = Some(seed);
+                ("200 OK", format!("seed {seed} staged for the next reset"))
+            }
+            Err(err) => ("400 Bad Request", err),
+        },
+        _ => ("404 Not Found", "only POST /seed is served".to_string()),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{message}",
+        message.len()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post(address: SocketAddr, path: &str, body: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(
+            stream,
+            "POST {path} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn stages_valid_seeds_only() {
+        let request = SeedRequest::default();
+        let address = request.listen("127.0.0.1:0").unwrap();
+
+        assert!(post(address, "/seed", "42\n").starts_with("HTTP/1.1 200"));
+        assert!(post(address, "/seed", "-1").starts_with("HTTP/1.1 400"));
+        assert!(post(address, "/reset", "7").starts_with("HTTP/1.1 404"));
+        assert_eq!(request.take(), Some(42));
+        assert_eq!(request.take(), None);
+    }
+}
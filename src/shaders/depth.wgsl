@@ -0,0 +1,23 @@
+#import bevy_pbr::mesh_view_bindings
+
+@group(1) @binding(0)
+var<uniform> max_depth: f32;
+
+struct FragmentInput {
+    #import bevy_pbr::mesh_vertex_output
+};
+
+// The render target stores sRGB, undo the encoding so that stored values are linear in depth
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        return value / 12.92;
+    }
+    return pow((value + 0.055) / 1.055, 2.4);
+}
+
+@fragment
+fn fragment(in: FragmentInput) -> @location(0) vec4<f32> {
+    let depth = clamp(distance(in.world_position.xyz, view.world_position) / max_depth, 0.0, 1.0);
+    let value = srgb_to_linear(depth);
+    return vec4<f32>(value, value, value, 1.0);
+}
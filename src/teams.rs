@@ -0,0 +1,52 @@
+use bevy::prelude::*;
+use serde::Serialize;
+
+use crate::config::*;
+
+/// Agent colors by team, reused when there are more teams than colors
+pub(crate) const TEAM_COLORS: [Color; 6] = [
+    Color::RED,
+    Color::BLUE,
+    Color::GREEN,
+    Color::YELLOW,
+    Color::PURPLE,
+    Color::CYAN,
+];
+
+// ----------
+// Components
+// ----------
+
+#[derive(Component, Serialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(transparent)]
+pub struct Team(pub usize);
+
+// ---------
+// Resources
+// ---------
+
+/// Score of every team in the current round, kept by the game mode
+#[derive(Resource, Serialize, Clone, Debug, Default)]
+#[serde(transparent)]
+pub(crate) struct TeamScores(pub(crate) Vec<u32>);
+
+impl TeamScores {
+    /// Team with strictly more kills than every other team
+    pub(crate) fn leader(&self) -> Option<usize> {
+        let best = *self.0.iter().max()?;
+        let mut leaders = self.0.iter().enumerate().filter(|(_, s)| **s == best);
+        match (leaders.next(), leaders.next()) {
+            (Some((team, _)), None) => Some(team),
+            _ => None,
+        }
+    }
+}
+
+// -------
+// Systems
+// -------
+
+pub(crate) fn reset_team_scores(config: Res<GameConfig>, mut scores: ResMut<TeamScores>) {
+    let teams = config.teams.as_ref().map_or(0, |teams| teams.sizes.len());
+    scores.0 = vec![0; teams];
+}
@@ -0,0 +1,501 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+use bevy_mod_raycast::RaycastSource;
+use bevy_rapier3d::prelude::*;
+use rand::Rng;
+use serde::Serialize;
+
+use crate::{actors::*, config::*, events::*, game::*, level::*, observations::*};
+
+const PROJECTILE_RADIUS: f32 = 0.2;
+/// How far from a projectile's center at impact the wall cell it destroys may be
+const PROJECTILE_WALL_REACH: f32 = 1.0;
+
+// ----------
+// Components
+// ----------
+
+/// Ammunition and timers of a single gun
+#[derive(Serialize, Clone, Debug)]
+pub(crate) struct Weapon {
+    #[serde(skip)]
+    stats: WeaponConfig,
+    /// Rounds left in the magazine
+    pub(crate) magazine: u32,
+    /// Rounds left outside the magazine
+    pub(crate) reserve: u32,
+    /// Seconds until the next shot can be fired
+    pub(crate) cooldown: f32,
+    /// Seconds until the reload in progress completes, 0 when not reloading
+    pub(crate) reload: f32,
+}
+
+impl Weapon {
+    pub(crate) fn new(stats: WeaponConfig) -> Self {
+        Weapon {
+            magazine: stats.magazine_size,
+            reserve: stats.reserve_ammo,
+            cooldown: 0.0,
+            reload: 0.0,
+            stats,
+        }
+    }
+
+    pub(crate) fn stats(&self) -> &WeaponConfig {
+        &self.stats
+    }
+
+    pub(crate) fn is_reloading(&self) -> bool {
+        self.reload > 0.0
+    }
+
+    /// Spend a round if the gun is ready; an empty magazine starts reloading on its own
+    pub(crate) fn try_fire(&mut self) -> bool {
+        if self.cooldown > 0.0 || self.is_reloading() || self.magazine == 0 {
+            return false;
+        }
+
+        self.magazine -= 1;
+        self.cooldown = self.stats.fire_interval;
+        if self.magazine == 0 {
+            self.start_reload();
+        }
+        true
+    }
+
+    /// Does nothing while reloading, with a full magazine or without reserve ammo
+    pub(crate) fn start_reload(&mut self) {
+        if self.is_reloading() || self.magazine == self.stats.magazine_size || self.reserve == 0 {
+            return;
+        }
+
+        self.reload = self.stats.reload_time;
+        if !self.is_reloading() {
+            self.finish_reload();
+        }
+    }
+
+    fn finish_reload(&mut self) {
+        let rounds = (self.stats.magazine_size - self.magazine).min(self.reserve);
+        self.magazine += rounds;
+        self.reserve -= rounds;
+    }
+
+    fn tick(&mut self, delta: f32) {
+        self.cooldown = (self.cooldown - delta).max(0.0);
+        if self.is_reloading() {
+            self.reload = (self.reload - delta).max(0.0);
+            if !self.is_reloading() {
+                self.finish_reload();
+            }
+        }
+    }
+}
+
+/// Every weapon of the catalog carried by an actor, in `GameConfig::weapons` order
+#[derive(Component, Serialize, Clone, Debug)]
+pub(crate) struct Arsenal {
+    /// Index of the weapon in hand
+    pub(crate) selected: usize,
+    pub(crate) weapons: Vec<Weapon>,
+}
+
+impl Arsenal {
+    pub(crate) fn new(catalog: &[WeaponConfig]) -> Self {
+        Arsenal {
+            selected: 0,
+            weapons: catalog.iter().cloned().map(Weapon::new).collect(),
+        }
+    }
+
+    pub(crate) fn current(&self) -> &Weapon {
+        &self.weapons[self.selected]
+    }
+
+    pub(crate) fn current_mut(&mut self) -> &mut Weapon {
+        &mut self.weapons[self.selected]
+    }
+
+    /// Switch to the next weapon of the catalog, wrapping around
+    pub(crate) fn select_next(&mut self) {
+        self.selected = (self.selected + 1) % self.weapons.len();
+    }
+}
+
+/// A rocket in flight
+#[derive(Component)]
+pub(crate) struct Projectile {
+    owner: AgentId,
+    /// Index of the weapon in `GameConfig::weapons`
+    weapon: usize,
+    origin: Vec3,
+    age: f32,
+}
+
+// ---------
+// Resources
+// ---------
+
+#[derive(Resource)]
+pub(crate) struct ProjectileAssets {
+    mesh: Handle<Mesh>,
+    material: Handle<StandardMaterial>,
+}
+
+// -------
+// Systems
+// -------
+
+pub(crate) fn setup_projectile_assets(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    commands.insert_resource(ProjectileAssets {
+        mesh: meshes.add(Mesh::from(shape::UVSphere {
+            radius: PROJECTILE_RADIUS,
+            ..default()
+        })),
+        material: materials.add(Color::ORANGE.into()),
+    });
+}
+
+pub(crate) fn update_weapons(time: Res<Time>, mut arsenals: Query<&mut Arsenal>) {
+    for mut arsenal in arsenals.iter_mut() {
+        for weapon in arsenal.weapons.iter_mut() {
+            weapon.tick(time.delta_seconds());
+        }
+    }
+}
+
+/// Shotgun pellets are cast against the physics colliders of walls and living actors
+pub(crate) fn fire_shotgun(
+    rapier_context: Res<RapierContext>,
+    config: Res<GameConfig>,
+    mut rng: ResMut<GameRng>,
+    guns: Query<
+        (&Parent, &GlobalTransform),
+        (With<RaycastSource<RaycastMarker>>, Without<LidarRay>),
+    >,
+    actors: Query<(&Transform, &Actor, &AgentId)>,
+    mut walls: Query<&mut Wall>,
+    mut last_shot_query: Query<&mut LastShot>,
+    round_timer: Res<RoundTimer>,
+    mut recent_shots: Option<ResMut<RecentShots>>,
+
+    mut gunshot_event: EventReader<EventGunShot>,
+    mut event_damage: EventWriter<EventDamage>,
+    mut event_shot_fired: EventWriter<EventShotFired>,
+) {
+    for gunshot_event in gunshot_event.iter() {
+        let weapon = &config.weapons[gunshot_event.weapon];
+        if weapon.kind != WeaponKind::Shotgun {
+            continue;
+        }
+
+        let Some((shooter, gun)) = guns.iter().find(|(p, _)| {
+            actors
+                .get(p.get())
+                .map_or(false, |(.., id)| *id == gunshot_event.from)
+        }) else {
+            continue;
+        };
+
+        let targets =
+            |e: Entity| walls.contains(e) || actors.get(e).map_or(false, |(_, a, _)| a.health > 0);
+        let filter = QueryFilter::default()
+            .exclude_rigid_body(shooter.get())
+            .exclude_sensors()
+            .predicate(&targets);
+
+        let origin = gun.translation();
+        let half_spread = weapon.spread.to_radians() / 2.0;
+        let mut outcome = ShotOutcome::Missed;
+        let mut wall_hits = Vec::new();
+
+        for _ in 0..weapon.pellets {
+            let yaw = if half_spread > 0.0 {
+                rng.rng.gen_range(-half_spread..=half_spread)
+            } else {
+                0.0
+            };
+            let direction = Quat::from_rotation_y(yaw) * gun.forward();
+            let hit = rapier_context.cast_ray(origin, direction, weapon.range, true, filter);
+            let to = origin + direction * hit.map_or(weapon.range, |(_, toi)| toi);
+
+            if let Some(recent_shots) = recent_shots.as_mut() {
+                recent_shots.push(RecentShot {
+                    from: origin,
+                    to,
+                    time: round_timer.0.elapsed_secs(),
+                });
+            }
+
+            let Some((hit_entity, _)) = hit else {
+                continue;
+            };
+
+            if let Ok((transform, _, enemy)) = actors.get(hit_entity) {
+                let critical =
+                    is_critical_hit(config.damage.critical.as_ref(), to, transform.translation);
+                event_damage.send(EventDamage {
+                    from: gunshot_event.from,
+                    to: *enemy,
+                    damage: weapon.damage,
+                    distance: origin.distance(to),
+                    critical,
+                });
+                outcome = ShotOutcome::HitAgent;
+            } else {
+                wall_hits.push((hit_entity, to + direction * WALL_HIT_DEPTH));
+            }
+        }
+
+        // Pellets into a cell that another pellet destroyed find no cell there
+        for (wall, inside) in wall_hits {
+            if destroy_wall_cell(&mut walls, wall, inside, 0.0) && outcome == ShotOutcome::Missed {
+                outcome = ShotOutcome::DestroyedWall;
+            }
+        }
+
+        if let Ok(mut last_shot) = last_shot_query.get_mut(shooter.get()) {
+            last_shot.hit = outcome == ShotOutcome::HitAgent;
+        }
+        event_shot_fired.send(EventShotFired {
+            from: gunshot_event.from,
+            outcome,
+        });
+    }
+}
+
+pub(crate) fn launch_projectiles(
+    mut commands: Commands,
+    config: Res<GameConfig>,
+    assets: Res<ProjectileAssets>,
+    guns: Query<
+        (&Parent, &GlobalTransform),
+        (With<RaycastSource<RaycastMarker>>, Without<LidarRay>),
+    >,
+    actors: Query<&AgentId>,
+    mut gunshot_event: EventReader<EventGunShot>,
+) {
+    for gunshot_event in gunshot_event.iter() {
+        let weapon = &config.weapons[gunshot_event.weapon];
+        if weapon.kind != WeaponKind::Projectile {
+            continue;
+        }
+
+        let Some((_, gun)) = guns.iter().find(|(p, _)| {
+            actors
+                .get(p.get())
+                .map_or(false, |id| *id == gunshot_event.from)
+        }) else {
+            continue;
+        };
+
+        // spawn outside of the shooter's collider
+        let direction = gun.forward();
+        let origin = gun.translation() + direction * (ACTOR_RADIUS + PROJECTILE_RADIUS + 0.1);
+
+        commands.spawn((
+            PbrBundle {
+                mesh: assets.mesh.clone(),
+                material: assets.material.clone(),
+                transform: Transform::from_translation(origin),
+                ..default()
+            },
+            RigidBody::Dynamic,
+            Collider::ball(PROJECTILE_RADIUS),
+            Sensor,
+            GravityScale(0.0),
+            Ccd::enabled(),
+            ActiveEvents::COLLISION_EVENTS,
+            Velocity::linear(direction * weapon.projectile_speed),
+            Projectile {
+                owner: gunshot_event.from,
+                weapon: gunshot_event.weapon,
+                origin,
+                age: 0.0,
+            },
+        ));
+    }
+}
+
+/// Projectiles explode when they touch a wall or a living actor, or when their lifetime is over
+pub(crate) fn explode_projectiles(
+    mut commands: Commands,
+    time: Res<Time>,
+    config: Res<GameConfig>,
+    mut projectiles: Query<(Entity, &Transform, &mut Projectile)>,
+    actors: Query<(Entity, &Transform, &Actor, &AgentId), Without<Projectile>>,
+    mut walls: Query<&mut Wall>,
+    mut last_shot_query: Query<&mut LastShot>,
+    round_timer: Res<RoundTimer>,
+    mut recent_shots: Option<ResMut<RecentShots>>,
+
+    mut collision_events: EventReader<CollisionEvent>,
+    mut event_damage: EventWriter<EventDamage>,
+    mut event_shot_fired: EventWriter<EventShotFired>,
+) {
+    let is_target =
+        |e: Entity| walls.contains(e) || actors.get(e).map_or(false, |(_, _, a, _)| a.health > 0);
+
+    let mut impacts: HashMap<Entity, Entity> = HashMap::new();
+    for collision_event in collision_events.iter() {
+        let CollisionEvent::Started(a, b, _) = collision_event else {
+            continue;
+        };
+        for (projectile, other) in [(*a, *b), (*b, *a)] {
+            if projectiles.contains(projectile) && is_target(other) {
+                impacts.entry(projectile).or_insert(other);
+            }
+        }
+    }
+
+    for (entity, transform, mut projectile) in projectiles.iter_mut() {
+        let weapon = &config.weapons[projectile.weapon];
+        projectile.age += time.delta_seconds();
+
+        let impact = impacts.get(&entity).copied();
+        if impact.is_none() && projectile.age < weapon.lifetime {
+            continue;
+        }
+
+        commands.entity(entity).despawn_recursive();
+
+        let center = transform.translation;
+        let mut outcome = ShotOutcome::Missed;
+        if let Some(wall) = impact {
+            if destroy_wall_cell(&mut walls, wall, center, PROJECTILE_WALL_REACH) {
+                outcome = ShotOutcome::DestroyedWall;
+            }
+        }
+
+        let mut owner_entity = None;
+        for (actor_entity, actor_transform, actor, id) in actors.iter() {
+            if *id == projectile.owner {
+                owner_entity = Some(actor_entity);
+                continue;
+            }
+            if actor.health == 0 {
+                continue;
+            }
+
+            let distance = actor_transform.translation.distance(center);
+            let scale = if impact == Some(actor_entity) {
+                1.0
+            } else if distance < weapon.splash_radius {
+                1.0 - distance / weapon.splash_radius
+            } else {
+                continue;
+            };
+
+            event_damage.send(EventDamage {
+                from: projectile.owner,
+                to: *id,
+                damage: ((weapon.damage as f32 * scale).round() as u16).max(1),
+                distance: projectile.origin.distance(center),
+                critical: false,
+            });
+            outcome = ShotOutcome::HitAgent;
+        }
+
+        if let Some(mut last_shot) = owner_entity.and_then(|e| last_shot_query.get_mut(e).ok()) {
+            last_shot.hit = outcome == ShotOutcome::HitAgent;
+        }
+        if let Some(recent_shots) = recent_shots.as_mut() {
+            recent_shots.push(RecentShot {
+                from: projectile.origin,
+                to: center,
+                time: round_timer.0.elapsed_secs(),
+            });
+        }
+        event_shot_fired.send(EventShotFired {
+            from: projectile.owner,
+            outcome,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two rounds per magazine and three in reserve
+    fn weapon(reload_time: f32) -> Weapon {
+        Weapon::new(WeaponConfig {
+            fire_interval: 0.5,
+            magazine_size: 2,
+            reserve_ammo: 3,
+            reload_time,
+            ..default()
+        })
+    }
+
+    #[test]
+    fn cooldown_between_shots() {
+        let mut weapon = weapon(1.0);
+        assert!(weapon.try_fire());
+        assert!(!weapon.try_fire());
+        weapon.tick(0.25);
+        assert!(!weapon.try_fire());
+        weapon.tick(0.25);
+        assert!(weapon.try_fire());
+    }
+
+    #[test]
+    fn empty_magazine_reloads_from_reserve() {
+        let mut weapon = weapon(1.0);
+        weapon.try_fire();
+        weapon.tick(0.5);
+        weapon.try_fire();
+        assert_eq!(weapon.magazine, 0);
+        assert!(weapon.is_reloading());
+
+        weapon.tick(0.5);
+        assert!(!weapon.try_fire());
+        weapon.tick(0.5);
+        assert!(!weapon.is_reloading());
+        assert_eq!((weapon.magazine, weapon.reserve), (2, 1));
+    }
+
+    #[test]
+    fn reload_is_limited_by_reserve() {
+        let mut weapon = weapon(0.0);
+        for _ in 0..4 {
+            weapon.try_fire();
+            weapon.tick(0.5);
+        }
+        // The second reload only had one round left
+        assert_eq!((weapon.magazine, weapon.reserve), (1, 0));
+
+        weapon.try_fire();
+        assert_eq!(weapon.magazine, 0);
+        assert!(!weapon.is_reloading());
+        weapon.tick(0.5);
+        assert!(!weapon.try_fire());
+    }
+
+    #[test]
+    fn manual_reload() {
+        let mut weapon = weapon(1.0);
+        weapon.start_reload();
+        assert!(!weapon.is_reloading(), "the magazine is full");
+
+        weapon.try_fire();
+        weapon.start_reload();
+        assert!(weapon.is_reloading());
+        weapon.tick(1.0);
+        assert_eq!((weapon.magazine, weapon.reserve), (2, 2));
+    }
+
+    #[test]
+    fn weapon_selection_wraps() {
+        let mut arsenal = Arsenal::new(&GameConfig::default().weapons);
+        for expected in [1, 2, 0] {
+            arsenal.select_next();
+            assert_eq!(arsenal.selected, expected);
+        }
+    }
+}